@@ -19,9 +19,10 @@ fn main() {
     iter_in
         .zip(out_tests.chain(iter::repeat(("none".to_owned(), ""))))
         .for_each(|((name, input_data), (out_name, output_data))| {
-            (name == out_name).then_some(()).unwrap_or_else(|| {
-                panic!("expected {name} test case section spec.processed.md, got {out_name}")
-            });
+            assert_eq!(
+                name, out_name,
+                "expected {name} test case section spec.processed.md, got {out_name}"
+            );
             writeln!(f, "#[test]").unwrap();
             writeln!(f, "fn {name}() {{").unwrap();
             writeln!(f, "    assert_process_eq!(").unwrap();
@@ -0,0 +1,7 @@
+// ANCHOR: main
+fn main() {
+    // ANCHOR: greeting
+    println!("Hello, world!");
+    // ANCHOR_END: greeting
+}
+// ANCHOR_END: main
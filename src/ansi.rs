@@ -0,0 +1,241 @@
+//! ANSI escape sequences in command output: stripped, so that colored
+//! output doesn't leave garbage in documents, or SGR colors and styles
+//! rendered as HTML.
+use std::fmt::Write as _;
+
+/// What to do with escape sequences in the output of an action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ansi {
+    #[default]
+    Strip,
+    Keep,
+    /// Render colors and styles as `<span style=...>` in a `<pre>` block.
+    Html,
+}
+
+impl std::str::FromStr for Ansi {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(Self::Strip),
+            "keep" => Ok(Self::Keep),
+            "html" => Ok(Self::Html),
+            _ => Err(format!("expected `strip`, `keep` or `html`, got {s:?}")),
+        }
+    }
+}
+
+/// Removes all escape sequences.
+pub fn strip(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for token in tokens(data) {
+        if let Token::Text(text) = token {
+            out.extend_from_slice(text);
+        }
+    }
+    out
+}
+
+/// Escapes `data` for HTML, with SGR sequences turned into spans,
+/// and other escape sequences removed.
+pub fn to_html(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len());
+    let mut style = Style::default();
+    let mut open = false;
+    for token in tokens(data) {
+        match token {
+            Token::Text(text) => {
+                let css = style.css();
+                if !open && !css.is_empty() {
+                    let _ = write!(out, "<span style=\"{css}\">");
+                    open = true;
+                }
+                for c in String::from_utf8_lossy(text).chars() {
+                    match c {
+                        '&' => out += "&amp;",
+                        '<' => out += "&lt;",
+                        '>' => out += "&gt;",
+                        c => out.push(c),
+                    }
+                }
+            }
+            Token::Sgr(params) => {
+                let before = style.clone();
+                style.apply(params);
+                if open && style != before {
+                    out += "</span>";
+                    open = false;
+                }
+            }
+            Token::Other => (),
+        }
+    }
+    if open {
+        out += "</span>";
+    }
+    out
+}
+
+enum Token<'a> {
+    Text(&'a [u8]),
+    /// Parameters of a Select Graphic Rendition sequence, `ESC [ ... m`.
+    Sgr(&'a [u8]),
+    Other,
+}
+
+fn tokens(mut data: &[u8]) -> impl Iterator<Item = Token<'_>> {
+    std::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        let at = data.iter().position(|x| *x == 0x1b).unwrap_or(data.len());
+        if at > 0 {
+            let (text, rest) = data.split_at(at);
+            data = rest;
+            return Some(Token::Text(text));
+        }
+        let (token, len) = match data.get(1) {
+            // Control Sequence: parameters and intermediates, then a final byte
+            Some(b'[') => match data[2..].iter().position(|x| (0x40..=0x7e).contains(x)) {
+                Some(end) if data[2 + end] == b'm' => (Token::Sgr(&data[2..2 + end]), end + 3),
+                Some(end) => (Token::Other, end + 3),
+                None => (Token::Other, data.len()),
+            },
+            // Operating System Command, like a title or a hyperlink,
+            // up to BEL or `ESC \`
+            Some(b']') => {
+                let end = (2..data.len()).find_map(|i| match data[i] {
+                    0x07 => Some(i + 1),
+                    0x1b if data.get(i + 1) == Some(&b'\\') => Some(i + 2),
+                    _ => None,
+                });
+                (Token::Other, end.unwrap_or(data.len()))
+            }
+            Some(_) => (Token::Other, 2),
+            None => (Token::Other, 1),
+        };
+        data = &data[len..];
+        Some(token)
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Style {
+    color: Option<String>,
+    background: Option<String>,
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    strike: bool,
+}
+
+impl Style {
+    fn apply(&mut self, params: &[u8]) {
+        let params = String::from_utf8_lossy(params);
+        let mut params = params.split([';', ':']).map(|x| match x {
+            "" => 0,
+            x => x.parse::<u8>().unwrap_or(u8::MAX),
+        });
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Self::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                9 => self.strike = true,
+                22 => (self.bold, self.dim) = (false, false),
+                23 => self.italic = false,
+                24 => self.underline = false,
+                29 => self.strike = false,
+                30..=37 => self.color = Some(PALETTE[param as usize - 30].to_owned()),
+                38 => self.color = extended_color(&mut params),
+                39 => self.color = None,
+                40..=47 => self.background = Some(PALETTE[param as usize - 40].to_owned()),
+                48 => self.background = extended_color(&mut params),
+                49 => self.background = None,
+                90..=97 => self.color = Some(PALETTE[param as usize - 82].to_owned()),
+                100..=107 => self.background = Some(PALETTE[param as usize - 92].to_owned()),
+                _ => (),
+            }
+        }
+    }
+
+    fn css(&self) -> String {
+        let mut css = Vec::new();
+        if let Some(color) = &self.color {
+            css.push(format!("color:{color}"));
+        }
+        if let Some(background) = &self.background {
+            css.push(format!("background-color:{background}"));
+        }
+        if self.bold {
+            css.push("font-weight:bold".to_owned());
+        }
+        if self.dim {
+            css.push("opacity:0.7".to_owned());
+        }
+        if self.italic {
+            css.push("font-style:italic".to_owned());
+        }
+        match (self.underline, self.strike) {
+            (true, true) => css.push("text-decoration:underline line-through".to_owned()),
+            (true, false) => css.push("text-decoration:underline".to_owned()),
+            (false, true) => css.push("text-decoration:line-through".to_owned()),
+            (false, false) => (),
+        }
+        css.join(";")
+    }
+}
+
+/// The 16 basic colors, then the bright ones.
+const PALETTE: [&str; 16] = [
+    "#000000", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
+    "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
+];
+
+/// `5;N` from the 256 color palette, or `2;R;G;B`.
+fn extended_color(params: &mut impl Iterator<Item = u8>) -> Option<String> {
+    match params.next()? {
+        5 => Some(match params.next()? {
+            n @ 0..=15 => PALETTE[n as usize].to_owned(),
+            n @ 16..=231 => {
+                let level = |x: u8| if x == 0 { 0 } else { 55 + 40 * x };
+                let n = n - 16;
+                format!(
+                    "#{:02x}{:02x}{:02x}",
+                    level(n / 36),
+                    level(n / 6 % 6),
+                    level(n % 6)
+                )
+            }
+            n => {
+                let gray = 8 + 10 * (n - 232);
+                format!("#{gray:02x}{gray:02x}{gray:02x}")
+            }
+        }),
+        2 => {
+            let (r, g, b) = (params.next()?, params.next()?, params.next()?);
+            Some(format!("#{r:02x}{g:02x}{b:02x}"))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ansi() {
+        let link = b"\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x07 \x1b[2Kdone";
+        assert_eq!(strip(link), b"link done");
+        assert_eq!(
+            to_html(b"\x1b[38;5;208mo\x1b[48;2;1;2;3mk\x1b[39;49m <"),
+            "<span style=\"color:#ff8700\">o</span>\
+             <span style=\"color:#ff8700;background-color:#010203\">k</span> &lt;"
+        );
+    }
+}
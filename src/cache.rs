@@ -0,0 +1,84 @@
+//! Content-addressed cache of command outputs, so that unchanged commands
+//! don't have to run again.
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Directory with one file per command output, named by [`Key`].
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    /// Run everything again, overwriting the outputs.
+    refresh: bool,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf, refresh: bool) -> Self {
+        Self { dir, refresh }
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<Vec<u8>>> {
+        if self.refresh {
+            return Ok(None);
+        }
+        match fs::read(self.dir.join(&key.0)) {
+            Ok(output) => Ok(Some(output)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading cache in {:?}", self.dir)),
+        }
+    }
+
+    pub fn put(&self, key: &Key, output: &[u8]) -> Result<()> {
+        let path = self.dir.join(&key.0);
+        let tmp = path.with_extension("tmp");
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&tmp, output))
+            .and_then(|_| fs::rename(&tmp, &path))
+            .with_context(|| format!("writing cache in {:?}", self.dir))
+    }
+}
+
+/// Hash of everything that makes up a command and its output.
+#[derive(Debug)]
+pub struct Key(String);
+
+impl Key {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Default)]
+pub struct KeyBuilder(Sha256);
+
+impl KeyBuilder {
+    /// Adds a field, length-prefixed so that fields can't run into each other.
+    pub fn add(&mut self, name: &str, value: impl AsRef<[u8]>) -> &mut Self {
+        let value = value.as_ref();
+        self.0.update(name.as_bytes());
+        self.0.update((value.len() as u64).to_le_bytes());
+        self.0.update(value);
+        self
+    }
+
+    /// Adds the content of a file the command depends on.
+    pub fn add_file(&mut self, workdir: &Path, path: &str) -> Result<&mut Self> {
+        let content = fs::read(workdir.join(path))
+            .with_context(|| format!("failed to read cache input {path:?}"))?;
+        Ok(self.add("input", path).add("content", content))
+    }
+
+    pub fn finish(self) -> Key {
+        Key(self
+            .0
+            .finalize()
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect())
+    }
+}
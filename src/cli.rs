@@ -2,6 +2,7 @@
 use std::{
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
 use clap::Parser;
@@ -14,12 +15,12 @@ use clap::Parser;
 #[clap(name = "mdsh", version = env!("CARGO_PKG_VERSION"))]
 pub struct Opt {
     /// Path to the markdown files. `-` for stdin.
-    #[clap(
-        short = 'i',
-        long = "inputs",
-        alias = "input",
-        default_value = "./README.md"
-    )]
+    ///
+    /// Directories and globs like `'docs/**/*.md'` stand for the markdown
+    /// files in them that have actions, except for those that
+    /// `.gitignore` or `.mdshignore` ignore [defaults to the `inputs` of the
+    /// configuration, or `./README.md`].
+    #[clap(short = 'i', long = "inputs", alias = "input")]
     pub inputs: Vec<FileArg>,
 
     /// Path to the output file, `-` for stdout [defaults to updating the input file in-place].
@@ -30,17 +31,219 @@ pub struct Opt {
     #[clap(short = 'w', long = "work-dir")]
     pub work_dir: Option<PathBuf>,
 
-    /// Fail if the output is different from the input. Useful for CI.
+    /// Fail if the output is different from the input, with a diff of what
+    /// changed, and leave the files as they are. Useful for CI.
     ///
     /// Using `--frozen`, you can guarantee that developers update
-    /// documentation when they make a change. Just add `mdsh --frozen`
-    /// as a check to your continuous integration setup.
+    /// documentation when they make a change. Just add `mdsh --frozen
+    /// --trust-all` as a check to your continuous integration setup. Also
+    /// fails if output looks like it has a token or private key in it.
     #[clap(long = "frozen", conflicts_with = "clean")]
     pub frozen: bool,
 
     /// Remove all generated blocks.
     #[clap(long = "clean")]
     pub clean: bool,
+
+    /// Print what would change as a patch for `git apply`, instead of
+    /// changing any file.
+    #[clap(long = "diff", conflicts_with_all = ["clean", "frozen"])]
+    pub diff: bool,
+
+    /// Print the actions of the inputs instead of running them, with their
+    /// location, container, command, data, work dir, and the shell and
+    /// prologue or interpreter that they run with.
+    ///
+    /// Nothing gets executed or read, so it's safe on untrusted files.
+    /// `--plan=json` prints one JSON object per action and line.
+    #[clap(
+        long = "plan",
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text",
+        conflicts_with_all = ["clean", "frozen"],
+    )]
+    pub plan: Option<PlanFormat>,
+
+    /// Write how every action went to FILE, as JSON, JUnit XML or TAP:
+    /// its location, command, duration, exit status, output size and
+    /// whether its block changed.
+    #[clap(long = "report", num_args = 2, value_names = ["FORMAT", "FILE"])]
+    pub report: Option<Vec<String>>,
+
+    /// Directory of the trust store, with the commands that `mdsh allow`
+    /// allowed [defaults to `$XDG_DATA_HOME/mdsh/trust`].
+    #[clap(long = "trust-dir", value_name = "DIR", global = true)]
+    pub trust_dir: Option<PathBuf>,
+
+    /// Run the commands of the inputs whether `mdsh allow` allowed them or
+    /// not. For CI and hooks, which run the checked out commands anyway.
+    #[clap(long = "trust-all")]
+    pub trust_all: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<SubCommand>,
+
+    /// Execute code blocks in LANG with COMMAND, which gets the code on stdin.
+    ///
+    /// Python, JavaScript, Ruby and Perl blocks run with `python3`, `node`,
+    /// `ruby` and `perl` by default, and everything else with the `--shell`.
+    /// A shebang line in the block takes precedence.
+    #[clap(long = "interpreter", value_name = "LANG=COMMAND", value_parser = parse_interpreter)]
+    pub interpreters: Vec<(String, String)>,
+
+    /// Shell for commands and shell scripts, with its arguments.
+    ///
+    /// Commands run with `-c COMMAND`, scripts are passed on stdin
+    /// [defaults to `bash`].
+    #[clap(long = "shell", value_name = "COMMAND")]
+    pub shell: Option<String>,
+
+    /// Code that runs before every command and shell script. Empty to disable.
+    ///
+    /// Defaults to `set -euo pipefail` with bash and zsh, and to `set -eu` with other shells.
+    #[clap(long = "prologue", value_name = "CODE")]
+    pub prologue: Option<String>,
+
+    /// Kill commands that run for longer than DURATION, like `30s` or `2m`.
+    ///
+    /// Actions can set their own with `{timeout=DURATION}`. `0` means none.
+    #[clap(long = "timeout", value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Run all shell commands of a file in one shell, like a terminal session.
+    ///
+    /// The working directory, shell variables and functions carry over from
+    /// one command to the next. Code blocks in other languages still run
+    /// on their own.
+    #[clap(long = "session")]
+    pub session: bool,
+
+    /// Run every command in a shell of its own, even if the configuration
+    /// says otherwise.
+    #[clap(long = "no-session", conflicts_with = "session")]
+    pub no_session: bool,
+
+    /// Directory for the outputs of commands that declare `{inputs=...}`,
+    /// reused while the command, its stdin, `!` variables and inputs stay
+    /// the same.
+    #[clap(long = "cache-dir", value_name = "DIR", default_value = ".mdsh-cache")]
+    pub cache_dir: PathBuf,
+
+    /// Run all commands, without reading or writing the cache.
+    #[clap(long = "no-cache")]
+    pub no_cache: bool,
+
+    /// Run all commands, and replace their outputs in the cache.
+    #[clap(long = "refresh", conflicts_with = "no_cache")]
+    pub refresh: bool,
+
+    /// Run commands in Linux namespaces: as `nobody`, without network, with
+    /// a read-only file system and an empty `/tmp`.
+    ///
+    /// Commands can still read whatever you can, like `$HOME`, and see your
+    /// processes.
+    #[clap(long = "sandbox")]
+    pub sandbox: bool,
+
+    /// Let sandboxed commands write to PATH.
+    #[clap(long = "sandbox-write", value_name = "PATH", requires = "sandbox")]
+    pub sandbox_write: Vec<PathBuf>,
+
+    /// Run commands in an empty environment, plus `PATH` and the variables
+    /// allowed with `--env-allow`.
+    ///
+    /// Pins `COLUMNS=80`, `LANG=C.UTF-8`, `NO_COLOR=1`, `SOURCE_DATE_EPOCH=0`
+    /// and `TZ=UTC`, unless they are allowed too.
+    #[clap(long = "hermetic")]
+    pub hermetic: bool,
+
+    /// Forward NAME to commands in hermetic mode. `NAME*` forwards all
+    /// variables starting with NAME.
+    #[clap(long = "env-allow", value_name = "NAME", requires = "hermetic")]
+    pub env_allow: Vec<String>,
+
+    /// Directory that `<` includes and work dirs have to be in [defaults to
+    /// the closest one with a `.git`, from the directory of the input up].
+    #[clap(long = "root", value_name = "DIR")]
+    pub root: Option<PathBuf>,
+
+    /// Let `<` includes and work dirs be anywhere.
+    #[clap(long = "allow-outside-root", conflicts_with = "root")]
+    pub allow_outside_root: bool,
+
+    /// Carry on after failed actions, which keep their previous output,
+    /// and list all of them at the end.
+    #[clap(long = "keep-going")]
+    pub keep_going: bool,
+
+    /// Normalization rules of actions that don't set any, like
+    /// `"dates durations"`, until `MDSH_NORMALIZE` sets others.
+    #[clap(long = "normalize", value_name = "RULES")]
+    pub normalize: Option<String>,
+
+    /// Configuration file, with defaults for these options [defaults to
+    /// `mdsh.toml`, or `[package.metadata.mdsh]` in `Cargo.toml`, in the
+    /// project root].
+    #[clap(long = "config", value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum SubCommand {
+    /// Let FILES run their current `$` commands.
+    ///
+    /// mdsh doesn't run the commands of a file, unless they were allowed
+    /// before, so that cloning a repository and running mdsh doesn't run
+    /// whatever its markdown says. `--trust-all` runs them anyway.
+    Allow {
+        /// Documents, or directories and globs of them [defaults to the
+        /// inputs].
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    Junit,
+    Tap,
+}
+
+/// Parses `500ms`, `30s`, `2m` or `1h`, plain numbers being seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let expected = || format!("expected a duration like `30s`, got {s:?}");
+    let n: u64 = n.parse().map_err(|_| expected())?;
+    let secs = |factor: u64| {
+        n.checked_mul(factor)
+            .map(Duration::from_secs)
+            .ok_or_else(expected)
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        _ => Err(format!("unknown unit {unit:?} in duration {s:?}")),
+    }
+}
+
+fn parse_interpreter(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((lang, command)) if !lang.is_empty() && !command.trim().is_empty() => {
+            Ok((lang.to_owned(), command.to_owned()))
+        }
+        _ => Err(format!("expected LANG=COMMAND, got {s:?}")),
+    }
 }
 
 /// Possible file input (either a file name or `-`)
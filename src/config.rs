@@ -0,0 +1,279 @@
+//! Project configuration from `mdsh.toml`, or `[package.metadata.mdsh]` in
+//! `Cargo.toml`, so that everyone runs mdsh with the same options. Options
+//! given on the command line take precedence.
+use std::{
+    collections::BTreeMap as Map,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+
+use crate::{
+    cli::{self, FileArg, Opt},
+    executor::{Environment, Interpreters, Settings, Shell},
+    normalize::{self, Rule},
+};
+
+pub const FILE: &str = "mdsh.toml";
+
+/// Options that the configuration can set for all documents, or for some.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Options {
+    pub shell: Option<String>,
+    pub prologue: Option<String>,
+    #[serde(deserialize_with = "duration")]
+    pub timeout: Option<Duration>,
+    pub session: Option<bool>,
+    pub hermetic: Option<bool>,
+    pub env_allow: Option<Vec<String>>,
+    /// Commands by language, on top of the default ones.
+    pub interpreters: Map<String, String>,
+    #[serde(deserialize_with = "rules")]
+    pub normalize: Option<Vec<Rule>>,
+}
+
+fn duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(d)?;
+    cli::parse_duration(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn rules<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<Rule>>, D::Error> {
+    let s = String::deserialize(d)?;
+    normalize::parse(&s)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("{e:#}")))
+}
+
+impl Options {
+    /// The options given on the command line.
+    pub fn from_cli(opt: &Opt) -> Result<Self> {
+        Ok(Self {
+            shell: opt.shell.clone(),
+            prologue: opt.prologue.clone(),
+            timeout: opt.timeout,
+            session: match (opt.session, opt.no_session) {
+                (true, _) => Some(true),
+                (false, true) => Some(false),
+                (false, false) => None,
+            },
+            hermetic: opt.hermetic.then_some(true),
+            env_allow: (!opt.env_allow.is_empty()).then(|| opt.env_allow.clone()),
+            interpreters: opt.interpreters.iter().cloned().collect(),
+            normalize: opt.normalize.as_deref().map(normalize::parse).transpose()?,
+        })
+    }
+
+    /// Whether these options set the shell, prologue or an interpreter
+    /// that `other` doesn't set instead.
+    pub fn sets_runner(&self, other: &Self) -> bool {
+        self.shell.is_some() && other.shell.is_none()
+            || self.prologue.is_some() && other.prologue.is_none()
+            || self
+                .interpreters
+                .keys()
+                .any(|x| !other.interpreters.contains_key(x))
+    }
+
+    /// These options, with those that `other` sets instead.
+    pub fn merge(mut self, other: Self) -> Self {
+        self.shell = other.shell.or(self.shell);
+        self.prologue = other.prologue.or(self.prologue);
+        self.timeout = other.timeout.or(self.timeout);
+        self.session = other.session.or(self.session);
+        self.hermetic = other.hermetic.or(self.hermetic);
+        self.env_allow = other.env_allow.or(self.env_allow);
+        self.interpreters.extend(other.interpreters);
+        self.normalize = other.normalize.or(self.normalize);
+        self
+    }
+
+    /// Sets what these options are about in `settings`, with the defaults
+    /// for those that aren't set.
+    pub fn apply(&self, settings: &mut Settings) {
+        settings.interpreters = Interpreters::default();
+        for (lang, command) in &self.interpreters {
+            settings.interpreters.insert(lang.clone(), command.clone());
+        }
+        let shell = self.shell.clone().unwrap_or_else(|| "bash".to_owned());
+        settings.shell = Shell::new(shell, self.prologue.clone());
+        settings.timeout = self.timeout;
+        settings.session = self.session.unwrap_or_default();
+        settings.env = self.hermetic.unwrap_or_default().then(|| {
+            let mut allow = self.env_allow.clone().unwrap_or_default();
+            allow.push("PATH".to_owned());
+            Environment::hermetic(&allow)
+        });
+        settings.normalize = self.normalize.clone().unwrap_or_default();
+    }
+}
+
+/// Options for the documents that match `paths`.
+#[derive(Debug)]
+struct Override {
+    paths: GlobSet,
+    options: Options,
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The file of the configuration, if there is one.
+    pub path: Option<PathBuf>,
+    /// Where the configuration is, which its paths are relative to.
+    pub dir: PathBuf,
+    /// Documents to process when none are given.
+    pub inputs: Vec<FileArg>,
+    /// Files that directory and glob inputs leave out.
+    ignore: GlobSet,
+    pub options: Options,
+    overrides: Vec<Override>,
+}
+
+impl Config {
+    /// The configuration in the project `root`, if there is one.
+    pub fn discover(root: &Path) -> Result<Option<Self>> {
+        let path = root.join(FILE);
+        if path.exists() {
+            return Self::load(&path).map(Some);
+        }
+        let path = root.join("Cargo.toml");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = read(&path)?;
+        let mut manifest: toml::Table =
+            toml::from_str(&content).with_context(|| format!("failed to parse {path:?}"))?;
+        let table = ["package", "metadata", "mdsh"]
+            .into_iter()
+            .try_fold(&mut manifest, |table, key| {
+                table.get_mut(key).and_then(toml::Value::as_table_mut)
+            })
+            .map(std::mem::take);
+        table
+            .map(|x| Self::parse(x, root))
+            .transpose()
+            .with_context(|| format!("invalid [package.metadata.mdsh] in {path:?}"))
+            .map(|x| x.map(|x| x.with_path(&path)))
+    }
+
+    /// The configuration in the file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let table =
+            toml::from_str(&read(path)?).with_context(|| format!("failed to parse {path:?}"))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Self::parse(table, dir)
+            .with_context(|| format!("invalid configuration {path:?}"))
+            .map(|x| x.with_path(path))
+    }
+
+    fn with_path(self, path: &Path) -> Self {
+        let path = self.dir.join(path.file_name().unwrap_or_default());
+        Self {
+            path: Some(path),
+            ..self
+        }
+    }
+
+    fn parse(mut table: toml::Table, dir: &Path) -> Result<Self> {
+        // paths are shown relative to the current directory if they can be
+        let dir = std::env::current_dir()
+            .ok()
+            .and_then(|x| dir.strip_prefix(x).ok())
+            .unwrap_or(dir)
+            .to_owned();
+        let inputs: Vec<String> = take(&mut table, "inputs")?;
+        let ignore: Vec<String> = take(&mut table, "ignore")?;
+        let mut overrides = Vec::new();
+        for (i, mut table) in take::<Vec<toml::Table>>(&mut table, "overrides")?
+            .into_iter()
+            .enumerate()
+        {
+            let paths: Vec<String> = take(&mut table, "paths")?;
+            overrides.push(Override {
+                paths: globs(&paths)?,
+                options: options(table).with_context(|| format!("in overrides[{i}]"))?,
+            });
+        }
+        Ok(Self {
+            inputs: inputs
+                .iter()
+                .map(|x| match x.as_str() {
+                    "-" => FileArg::StdHandle,
+                    x => FileArg::File(dir.join(x)),
+                })
+                .collect(),
+            ignore: globs(&ignore)?,
+            options: options(table)?,
+            overrides,
+            dir,
+            path: None,
+        })
+    }
+
+    /// Whether directory and glob inputs leave the file at `path` out.
+    pub fn ignores(&self, path: &Path) -> bool {
+        self.relative(path).is_some_and(|x| self.ignore.is_match(x))
+    }
+
+    /// The options for `input`, if overrides change them.
+    pub fn options_for(&self, input: &FileArg) -> Option<Options> {
+        let FileArg::File(path) = input else {
+            return None;
+        };
+        let path = self.relative(path)?;
+        let mut overrides = self
+            .overrides
+            .iter()
+            .filter(|x| x.paths.is_match(&path))
+            .peekable();
+        overrides.peek()?;
+        Some(overrides.fold(self.options.clone(), |options, x| {
+            options.merge(x.options.clone())
+        }))
+    }
+
+    /// `path` relative to the directory of the configuration, if it's in it.
+    fn relative(&self, path: &Path) -> Option<PathBuf> {
+        let dir = match self.dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => &self.dir,
+        };
+        let path = path.canonicalize().ok()?;
+        path.strip_prefix(dir.canonicalize().ok()?)
+            .ok()
+            .map(Path::to_owned)
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("failed to read file {path:?}"))
+}
+
+/// The value of `key` in `table`, which it's taken out of.
+fn take<T: DeserializeOwned + Default>(table: &mut toml::Table, key: &str) -> Result<T> {
+    let Some(value) = table.remove(key) else {
+        return Ok(T::default());
+    };
+    value.try_into().with_context(|| format!("invalid `{key}`"))
+}
+
+fn options(table: toml::Table) -> Result<Options> {
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+fn globs(patterns: &[String]) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for x in patterns {
+        let glob = GlobBuilder::new(x)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid glob {x:?}"))?;
+        set.add(glob);
+    }
+    Ok(set.build()?)
+}
@@ -0,0 +1,121 @@
+//! Unified diffs of what running mdsh would change in a document, for
+//! `--frozen` and `--diff`, with every hunk labeled by its action.
+use std::fmt::Write as _;
+
+use anyhow::Result;
+use similar::{ChangeTag, DiffTag, TextDiff};
+
+use crate::{cli::FileArg, MdPiece, Processor};
+
+/// Collects the line and the first line of the source of every action.
+struct Labels<'a> {
+    input: &'a str,
+    labels: &'a mut Vec<(usize, String)>,
+}
+
+impl<'a> Processor<'a> for Labels<'a> {
+    fn start(&mut self, input: &'a str, _input_pipe: &FileArg) {
+        self.input = input;
+    }
+
+    fn process_piece(&mut self, piece: MdPiece<'a>) -> Result<()> {
+        match piece {
+            MdPiece::Action((source, action)) => {
+                let (line, _) = crate::position(self.input, action.span.start);
+                let label = source.lines().next().unwrap_or_default().trim();
+                self.labels.push((line, label.to_owned()));
+            }
+            MdPiece::Container(container) => {
+                for piece in container.pieces {
+                    self.process_piece(piece)?;
+                }
+            }
+            MdPiece::FencedBlock(_) | MdPiece::Raw(_) => (),
+        }
+        Ok(())
+    }
+}
+
+/// Patch from `old` to `new` contents of the file at `path`, like
+/// `git diff` makes, so that `git apply` takes it.
+pub fn unified(path: &str, old: &str, new: &str) -> Result<String> {
+    let mut labels = Vec::new();
+    Labels {
+        input: "",
+        labels: &mut labels,
+    }
+    .process(old, &FileArg::File(path.into()))?;
+
+    let name = path.strip_prefix("./").unwrap_or(path);
+    let mut patch = match name.starts_with('/') {
+        true => format!("--- {name}\n+++ {name}\n"),
+        false => format!("--- a/{name}\n+++ b/{name}\n"),
+    };
+    let diff = TextDiff::from_lines(old, new);
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        let changed = hunk
+            .ops()
+            .iter()
+            .find(|x| x.tag() != DiffTag::Equal)
+            .map_or(0, |x| x.old_range().start + 1);
+        let _ = write!(patch, "{}", hunk.header());
+        // the generated block follows the action that changed it
+        if let Some((line, label)) = labels.iter().rev().find(|(line, _)| *line <= changed) {
+            let _ = write!(patch, " {name}:{line}: {label}");
+        }
+        patch.push('\n');
+        for change in hunk.iter_changes() {
+            let sign = match change.tag() {
+                ChangeTag::Equal => ' ',
+                ChangeTag::Delete => '-',
+                ChangeTag::Insert => '+',
+            };
+            let _ = write!(patch, "{sign}{}", change.value());
+            if change.missing_newline() {
+                patch.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    Ok(patch)
+}
+
+/// `patch` in the colors of `git diff`, for terminals.
+pub fn colorize(patch: &str) -> String {
+    patch
+        .lines()
+        .map(|line| {
+            let color = match line.as_bytes() {
+                [b'-', b'-', b'-', ..] | [b'+', b'+', b'+', ..] => "1",
+                [b'@', ..] => "36",
+                [b'-', ..] => "31",
+                [b'+', ..] => "32",
+                _ => return format!("{line}\n"),
+            };
+            format!("\x1b[{color}m{line}\x1b[0m\n")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified() {
+        let old = "# Title\n\n`> $ date`\n\n<!-- BEGIN mdsh -->\nMonday\n<!-- END mdsh -->\n";
+        let new = "# Title\n\n`> $ date`\n\n<!-- BEGIN mdsh -->\nTuesday\n<!-- END mdsh -->\n";
+        let patch = unified("./docs/a.md", old, new).unwrap();
+        assert_eq!(
+            patch,
+            "--- a/docs/a.md\n+++ b/docs/a.md\n\
+             @@ -3,5 +3,5 @@ docs/a.md:3: `> $ date`\n \
+             `> $ date`\n \n <!-- BEGIN mdsh -->\n-Monday\n+Tuesday\n <!-- END mdsh -->\n"
+        );
+        assert!(colorize(&patch).contains("\x1b[31m-Monday\x1b[0m\n\x1b[32m+Tuesday\x1b[0m\n"));
+        let patch = unified("/tmp/a.md", old, new).unwrap();
+        assert!(
+            patch.starts_with("--- /tmp/a.md\n+++ /tmp/a.md\n"),
+            "{patch}"
+        );
+    }
+}
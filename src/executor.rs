@@ -1,21 +1,80 @@
 use std::{
+    borrow::Cow,
     collections::BTreeMap as Map,
     ffi::OsStr,
-    fs::File,
     io::{Cursor, Read, Write},
-    process::{self, Stdio},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::{self, ChildStderr, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Error, Result};
 
-use crate::{MdPiece, BEGIN_MDSH, END_MDSH};
+use crate::{
+    ansi::{self, Ansi},
+    cache::{Cache, Key, KeyBuilder},
+    cli::FileArg,
+    filter, include,
+    normalize::{self, Rule},
+    report::{Failure, Outcome},
+    root::{self, Root},
+    sandbox::Sandbox,
+    secret::{self, Secrets},
+    session::Session,
+    MdPiece, BEGIN_MDSH, END_MDSH,
+};
 
 #[derive(Debug)]
 /// Actionable container: comment/code/link.
 pub struct Action<'a> {
     pub command: Command<'a>,
-    pub data_line: Option<&'a str>,
-    pub data: Option<&'a str>,
+    pub data_line: Option<Cow<'a, str>>,
+    pub data: Option<Cow<'a, str>>,
+    /// Source language of a code block, picks the interpreter
+    /// that executes its data.
+    pub lang: Option<Cow<'a, str>>,
+    pub kind: ContainerKind,
+    /// Bytes of the whole document that the action was parsed from,
+    /// set by [`crate::Processor::process`].
+    pub span: Range<usize>,
+}
+
+/// Markdown syntax that an [`Action`] is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerKind {
+    InlineCode,
+    CodeBlock,
+    Comment,
+    Link,
+}
+
+impl std::fmt::Display for ContainerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::InlineCode => "inline code",
+            Self::CodeBlock => "code block",
+            Self::Comment => "comment",
+            Self::Link => "link",
+        })
+    }
+}
+
+impl Action<'_> {
+    /// Detach from the parsed input, e.g. when it only lived
+    /// in the stripped content of a container.
+    pub fn into_owned(self) -> Action<'static> {
+        Action {
+            command: self.command.into_owned(),
+            data_line: self.data_line.map(|x| x.into_owned().into()),
+            data: self.data.map(|x| x.into_owned().into()),
+            lang: self.lang.map(|x| x.into_owned().into()),
+            kind: self.kind,
+            span: self.span,
+        }
+    }
 }
 
 /// Command to execute: get data, act on data.
@@ -23,6 +82,41 @@ pub struct Action<'a> {
 pub struct Command<'a> {
     pub in_type: InType,
     pub out_type: OutType<'a>,
+    /// `{key=value}` options of the action, see [`ATTRIBUTES`].
+    pub attributes: Map<String, String>,
+}
+
+impl Command<'_> {
+    pub fn into_owned(self) -> Command<'static> {
+        Command {
+            in_type: self.in_type,
+            out_type: match self.out_type {
+                OutType::Markdown => OutType::Markdown,
+                OutType::Environment => OutType::Environment,
+                OutType::CodeBlock(lang) => OutType::CodeBlock(lang.into_owned().into()),
+            },
+            attributes: self.attributes,
+        }
+    }
+}
+
+/// Attributes that actions understand:
+/// - `timeout`: like `--timeout`, `0` for none.
+/// - `cache`: whether to reuse the output of the command from the
+///   [`Cache`], by default only if it declares `inputs`.
+/// - `inputs`: comma separated files that the output of the command
+///   depends on, part of its cache key.
+/// - `ansi`: `strip`, `keep` or `html`, see [`Ansi`].
+/// - `normalize`: rules that rewrite volatile output, see [`normalize`].
+pub const ATTRIBUTES: &[&str] = &["timeout", "cache", "inputs", "ansi", "normalize"];
+
+/// Parsed [`Command::attributes`].
+struct Options {
+    timeout: Option<Duration>,
+    cache: bool,
+    inputs: Vec<String>,
+    ansi: Ansi,
+    normalize: Vec<Rule>,
 }
 
 /// How to get data: command output, file content, or raw.
@@ -48,29 +142,365 @@ pub enum OutType<'a> {
     /// `!` results are sourced as environment variables
     Environment,
     /// `> foo.yaml`, where lang name is `yaml`, results in code block
-    CodeBlock(&'a str),
+    CodeBlock(Cow<'a, str>),
 }
 
 impl<'a, W: Write> crate::Processor<'a> for TheProcessor<'a, W> {
+    fn start(&mut self, input: &'a str, input_pipe: &FileArg) {
+        self.input = input;
+        self.file = match input_pipe {
+            FileArg::StdHandle => "<stdin>".to_owned(),
+            FileArg::File(path) => path.display().to_string(),
+        };
+        let dir = match input_pipe {
+            FileArg::File(path) => path.parent().filter(|x| !x.as_os_str().is_empty()),
+            FileArg::StdHandle => None,
+        };
+        self.root = self.settings.root.resolve(dir.unwrap_or(Path::new(".")));
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.conclude(None)
+    }
+
     fn process_piece(&mut self, piece: MdPiece<'a>) -> Result<()> {
+        if self.last.is_some() {
+            if let MdPiece::FencedBlock(block) = piece {
+                return self.conclude(Some(block));
+            }
+            self.conclude(None)?;
+        }
         match piece {
-            MdPiece::FencedBlock => (),
+            MdPiece::FencedBlock(_) => (),
             MdPiece::Action((source, action)) => {
                 self.out.write_all(source.as_bytes())?;
-                self.process_action(action)?;
+                let (line, column) = crate::position(self.input, action.span.start);
+                self.line = line;
+                self.location = format!("{}:{line}:{column}", self.file);
+                let command = action.data_line.as_deref().or(action.data.as_deref());
+                let command = command.and_then(|x| x.lines().next()).unwrap_or_default();
+                let command = command.to_owned();
+                let executes = matches!(action.command.in_type, InType::Execute);
+                let started = Instant::now();
+                self.output_bytes = 0;
+                let result = self.process_action(action);
+                // errors may quote what the command printed, or its variables
+                let secrets = match &result {
+                    Ok(()) => Secrets::default(),
+                    Err(_) => self.secrets().unwrap_or_default(),
+                };
+                if let Some(outcomes) = self.outcomes.as_deref_mut() {
+                    outcomes.push(Outcome {
+                        file: self.file.clone(),
+                        line,
+                        command: command.clone(),
+                        duration: started.elapsed(),
+                        status: match &result {
+                            Ok(()) => executes.then_some(0),
+                            Err(e) => CommandFailed::find(e).and_then(|x| x.code),
+                        },
+                        output_bytes: self.output_bytes,
+                        changed: false,
+                        error: result
+                            .as_ref()
+                            .err()
+                            .map(|e| secrets.mask_str(&format!("{e:#}"))),
+                    });
+                }
+                if let Err(e) = result {
+                    let Some(failures) = self.failures.as_deref_mut() else {
+                        let snippet = crate::snippet(line, source, None);
+                        return Err(e.context(format!("{}\n{snippet}", self.location)));
+                    };
+                    eprintln!(
+                        "error: {}: {}",
+                        self.location,
+                        secrets.mask_str(&format!("{e:#}"))
+                    );
+                    failures.push(Failure::new(&self.file, Some(line), &command, &e, &secrets));
+                    self.last = Some(Last::Failed);
+                }
             }
-            MdPiece::RawLine(raw_line) => {
-                self.out.write_all(raw_line.as_bytes())?;
+            MdPiece::Raw(raw) => {
+                self.out.write_all(raw.as_bytes())?;
+            }
+            MdPiece::Container(container) => {
+                let outer = self.prefix.len();
+                self.prefix.push_str(&container.prefix);
+                for piece in container.pieces {
+                    self.process_piece(piece)?;
+                }
+                self.conclude(None)?;
+                self.prefix.truncate(outer);
             }
         }
         Ok(())
     }
 }
 
+/// Interpreters for executed code blocks, by the language of the block.
+/// Blocks in other languages, or without one, run with the [`Shell`].
+#[derive(Debug, Clone)]
+pub struct Interpreters(pub(crate) Map<String, String>);
+
+impl Default for Interpreters {
+    fn default() -> Self {
+        Self(
+            [
+                ("python", "python3"),
+                ("py", "python3"),
+                ("javascript", "node"),
+                ("js", "node"),
+                ("node", "node"),
+                ("ruby", "ruby"),
+                ("rb", "ruby"),
+                ("perl", "perl"),
+            ]
+            .into_iter()
+            .map(|(lang, command)| (lang.to_owned(), command.to_owned()))
+            .collect(),
+        )
+    }
+}
+
+impl Interpreters {
+    /// Runs blocks in `lang` with `command`, a program and its
+    /// whitespace separated arguments. The script comes on stdin.
+    pub fn insert(&mut self, lang: String, command: String) {
+        self.0.insert(lang, command);
+    }
+
+    /// Command that runs `script`: the one in its shebang line if it has
+    /// one, otherwise the one for `lang`, if any.
+    fn command(&self, lang: Option<&str>, script: &str) -> Option<process::Command> {
+        command_line(self.interpreter(lang, script)?)
+    }
+
+    fn interpreter<'s>(&'s self, lang: Option<&str>, script: &'s str) -> Option<&'s str> {
+        script
+            .strip_prefix("#!")
+            .and_then(|x| x.lines().next())
+            .or_else(|| self.0.get(lang?).map(String::as_str))
+    }
+}
+
+/// Shell that runs commands and shell scripts, and the prologue that
+/// goes before each of them.
+#[derive(Debug, Clone)]
+pub struct Shell {
+    pub(crate) command: String,
+    pub(crate) prologue: String,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new("bash".to_owned(), None)
+    }
+}
+
+impl Shell {
+    /// `command` is a program and its whitespace separated arguments,
+    /// which runs `-c COMMAND`, and scripts from stdin.
+    /// The `prologue` defaults to `set -euo pipefail` for bash and zsh,
+    /// and to `set -eu` for other shells, which may lack `pipefail`.
+    pub fn new(command: String, prologue: Option<String>) -> Self {
+        let prologue = prologue.unwrap_or_else(|| {
+            let program = command.split_whitespace().next().unwrap_or_default();
+            match program.rsplit('/').next() {
+                Some("bash" | "zsh") => "set -euo pipefail".to_owned(),
+                _ => "set -eu".to_owned(),
+            }
+        });
+        Self { command, prologue }
+    }
+
+    pub(crate) fn command(&self) -> Result<process::Command> {
+        command_line(&self.command).with_context(|| format!("invalid shell {:?}", self.command))
+    }
+
+    /// `code` with the prologue in front.
+    pub(crate) fn script<'c>(&self, code: &'c str) -> Cow<'c, str> {
+        if self.prologue.trim().is_empty() {
+            code.into()
+        } else {
+            format!("{}\n{code}", self.prologue).into()
+        }
+    }
+}
+
+/// What runs an executed action, to show it before it runs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Runner<'s> {
+    Shell { command: &'s str, prologue: &'s str },
+    Interpreter(&'s str),
+}
+
+impl std::fmt::Display for Runner<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Shell { command, prologue } if prologue.trim().is_empty() => {
+                write!(f, "`{command}`")
+            }
+            Self::Shell { command, prologue } => {
+                write!(f, "`{command}` after the prologue `{prologue}`")
+            }
+            Self::Interpreter(command) => write!(f, "`{command}`"),
+        }
+    }
+}
+
+/// Program and whitespace separated arguments.
+fn command_line(line: &str) -> Option<process::Command> {
+    let mut words = line.split_whitespace();
+    let mut cmd = process::Command::new(words.next()?);
+    cmd.args(words);
+    Some(cmd)
+}
+
+/// Environment that commands start from in hermetic mode, instead of
+/// mdsh's own, so that their output doesn't depend on where they run.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    variables: Map<String, String>,
+    /// Names of the variables that came from mdsh's environment.
+    pub forwarded: Vec<String>,
+}
+
+impl Environment {
+    /// Set for every command, unless forwarded from mdsh's environment.
+    pub const PINNED: &'static [(&'static str, &'static str)] = &[
+        ("COLUMNS", "80"),
+        ("LANG", "C.UTF-8"),
+        ("NO_COLOR", "1"),
+        ("SOURCE_DATE_EPOCH", "0"),
+        ("TZ", "UTC"),
+    ];
+
+    /// Pinned variables, plus those of mdsh's environment that are in
+    /// `allow`. A trailing `*` allows all variables with that prefix.
+    pub fn hermetic(allow: &[String]) -> Self {
+        let allowed = |name: &str| {
+            allow.iter().any(|x| match x.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == x,
+            })
+        };
+        let mut env = Self {
+            variables: Self::PINNED
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect(),
+            forwarded: Vec::new(),
+        };
+        for (k, v) in std::env::vars() {
+            if allowed(&k) {
+                env.forwarded.push(k.clone());
+                env.variables.insert(k, v);
+            }
+        }
+        env.forwarded.sort();
+        env
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(String::as_str)
+    }
+
+    /// Replaces the environment of `cmd`.
+    pub(crate) fn apply<'c>(&self, cmd: &'c mut process::Command) -> &'c mut process::Command {
+        cmd.env_clear().envs(&self.variables)
+    }
+}
+
+/// How actions get executed, the same for every input file.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub interpreters: Interpreters,
+    pub shell: Shell,
+    /// Kills commands that take longer, unless it's zero.
+    pub timeout: Option<Duration>,
+    /// Runs all shell commands of a document in one [`Session`].
+    pub session: bool,
+    /// Reuses the outputs of commands that ran before.
+    pub cache: Option<Cache>,
+    /// Runs commands isolated from the network and most of the file system.
+    pub sandbox: Option<Sandbox>,
+    /// Replaces mdsh's environment as the one that commands start from.
+    pub env: Option<Environment>,
+    /// Where `<` includes and work dirs have to be.
+    pub root: Root,
+    /// Fails instead of warning when output looks like it has a secret.
+    pub deny_leaks: bool,
+    /// Normalization rules of actions without their own, until
+    /// `MDSH_NORMALIZE` sets others.
+    pub normalize: Vec<Rule>,
+    /// Configuration file that set the shell, prologue or interpreters.
+    pub config: Option<PathBuf>,
+}
+
+impl Settings {
+    /// What runs `action`, if it executes anything, the same way
+    /// [`TheProcessor`] picks it.
+    pub fn runner<'s>(&'s self, action: &'s Action<'_>) -> Option<Runner<'s>> {
+        let InType::Execute = action.command.in_type else {
+            return None;
+        };
+        // a code block with only filters runs its script
+        let command = action
+            .data_line
+            .as_deref()
+            .map(|x| filter::split(x).map_or(x, |(command, _)| command))
+            .filter(|x| !x.is_empty());
+        let interpreter = match (command, action.data.as_deref()) {
+            (None, Some(script)) => self
+                .interpreters
+                .interpreter(action.lang.as_deref(), script),
+            _ => None,
+        };
+        Some(interpreter.map_or(
+            Runner::Shell {
+                command: &self.shell.command,
+                prologue: &self.shell.prologue,
+            },
+            Runner::Interpreter,
+        ))
+    }
+}
+
+/// What became of an action, as far as its block goes.
+#[derive(Debug)]
+enum Last {
+    Generated(Vec<u8>),
+    Failed,
+}
+
 #[derive(Debug, Default)]
 pub struct TheProcessor<'a, W> {
     variables: Map<String, String>,
     workdir: &'a OsStr,
+    settings: Settings,
+    session: Option<Session>,
+    input: &'a str,
+    file: String,
+    /// Canonical project root of the document, if confined.
+    root: Option<PathBuf>,
+    /// `!` variables that were defined as `secret`.
+    secrets: Vec<String>,
+    /// Where failed actions go, instead of stopping at the first one.
+    failures: Option<&'a mut Vec<Failure>>,
+    /// Where the outcome of every action goes.
+    outcomes: Option<&'a mut Vec<Outcome>>,
+    /// The last action, until it's known whether its previous block
+    /// follows.
+    last: Option<Last>,
+    /// Size of the output of the last action.
+    output_bytes: usize,
+    /// File, line and column of the action being processed.
+    location: String,
+    /// Line of the action being processed.
+    line: usize,
+    /// Line prefix of the containers the current action is nested in.
+    prefix: String,
     pub out: W,
 }
 
@@ -79,25 +509,205 @@ impl<'a, W: Write> TheProcessor<'a, W> {
         Self {
             variables: Default::default(),
             workdir,
+            settings: Default::default(),
+            session: None,
+            input: "",
+            file: String::new(),
+            root: None,
+            secrets: Vec::new(),
+            failures: None,
+            outcomes: None,
+            last: None,
+            output_bytes: 0,
+            location: String::new(),
+            line: 0,
+            prefix: String::new(),
             out,
         }
     }
 
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Records failed actions in `failures` and carries on, keeping their
+    /// previous blocks, instead of stopping at the first one.
+    pub fn with_failures(mut self, failures: Option<&'a mut Vec<Failure>>) -> Self {
+        self.failures = failures;
+        self
+    }
+
+    /// Records the outcome of every action in `outcomes`.
+    pub fn with_outcomes(mut self, outcomes: Option<&'a mut Vec<Outcome>>) -> Self {
+        self.outcomes = outcomes;
+        self
+    }
+
+    /// Settles the last action, `previous` being the block that it
+    /// generated before, if there is one. A failed action keeps it,
+    /// or gets a placeholder.
+    fn conclude(&mut self, previous: Option<&str>) -> Result<()> {
+        let changed = match self.last.take() {
+            None => return Ok(()),
+            Some(Last::Generated(block)) => previous.unwrap_or_default().as_bytes() != block,
+            Some(Last::Failed) => {
+                match previous {
+                    Some(block) => self.out.write_all(block.as_bytes())?,
+                    None => produce_fenced_block(
+                        &mut "<!-- mdsh: the action failed -->\n".as_bytes(),
+                        &mut Prefixed::new(&mut self.out, &self.prefix),
+                    )?,
+                }
+                previous.is_none()
+            }
+        };
+        if let Some(outcome) = self.outcomes.as_deref_mut().and_then(|x| x.last_mut()) {
+            outcome.changed = changed;
+        }
+        Ok(())
+    }
+
     /// Process parsed [`Action`]
     pub fn process_action(&mut self, action: Action<'a>) -> Result<()> {
+        let options = self.options(&action.command.attributes)?;
+        // `!` blocks written in the document, rather than read or executed,
+        // start on the line after a fence or comment opening without data
+        let first_line = matches!(action.command.in_type, InType::RawData)
+            .then(|| self.line + usize::from(action.data_line.is_none()));
+        let (data_line, filters) = match (&action.command.in_type, action.data_line.as_deref()) {
+            (InType::Execute | InType::Read, Some(data_line)) => {
+                let (data_line, filters) = filter::split(data_line)?;
+                // a code block with only filters runs its script
+                (Some(data_line).filter(|x| !x.is_empty()), filters)
+            }
+            (_, data_line) => (data_line, Vec::new()),
+        };
         let mut r = self
-            .get_data(action.command.in_type, action.data_line, action.data)
+            .get_data(
+                action.command.in_type,
+                data_line,
+                action.data.as_deref(),
+                action.lang.as_deref(),
+                &options,
+            )
             .context("getting data")?;
-        self.act_on_data(action.command.out_type, &mut r)
+        let mut data = Vec::with_capacity(8192);
+        r.read_to_end(&mut data).context("getting data")?;
+        // only filters and rules need text, other output stays byte for byte
+        if !filters.is_empty() || !options.normalize.is_empty() {
+            // so that patterns don't have to deal with colors
+            if options.ansi == Ansi::Strip {
+                data = ansi::strip(&data);
+            }
+            if !filters.is_empty() {
+                data = filter::apply(&filters, &data);
+            }
+            data = normalize::apply(&options.normalize, &data);
+        }
+        if !matches!(action.command.out_type, OutType::Environment) {
+            data = self.secrets()?.mask(&data);
+            if let Some(kind) = secret::leak(&data) {
+                let message = format!(
+                    "the output looks like it has a {kind} in it, mask it with `secret` or {}",
+                    secret::VARIABLE
+                );
+                if self.settings.deny_leaks {
+                    anyhow::bail!(message);
+                }
+                eprintln!("warning: {}: {message}", self.location);
+            }
+        }
+        self.output_bytes = data.len();
+        let block = self.act_on_data(
+            action.command.out_type,
+            &mut Cursor::new(data),
+            options.ansi,
+            first_line,
+        )?;
+        self.out.write_all(&block)?;
+        self.last = Some(Last::Generated(block));
+        Ok(())
+    }
+
+    /// The values of `secret` variables, and the variables and patterns
+    /// in [`secret::VARIABLE`].
+    fn secrets(&self) -> Result<Secrets> {
+        let mut secrets = Secrets::default();
+        for name in &self.secrets {
+            secrets.add_value(&self.variables[name]);
+        }
+        let Some(spec) = self.variables.get(secret::VARIABLE) else {
+            return Ok(secrets);
+        };
+        let inherited: Map<String, String> = match &self.settings.env {
+            Some(env) => env.variables.clone(),
+            None => std::env::vars().collect(),
+        };
+        for pattern in secret::parse(spec)? {
+            match pattern {
+                secret::Pattern::Regex(regex) => secrets.add_pattern(regex),
+                pattern => {
+                    let variables = self.variables.iter().chain(&inherited);
+                    for (_, value) in variables.filter(|(k, _)| pattern.matches(k)) {
+                        secrets.add_value(value);
+                    }
+                }
+            }
+        }
+        Ok(secrets)
+    }
+
+    fn options(&self, attributes: &Map<String, String>) -> Result<Options> {
+        if let Some(key) = attributes
+            .keys()
+            .find(|x| !ATTRIBUTES.contains(&x.as_str()))
+        {
+            anyhow::bail!("unknown attribute `{key}`, expected one of {ATTRIBUTES:?}");
+        }
+        let timeout = match attributes.get("timeout") {
+            Some(x) => Some(crate::cli::parse_duration(x).map_err(Error::msg)?),
+            None => self.settings.timeout,
+        };
+        let inputs = attributes
+            .get("inputs")
+            .map_or_else(Vec::new, |x| x.split(',').map(str::to_owned).collect());
+        let cache = match attributes.get("cache").map(String::as_str) {
+            // otherwise the files that it reads aren't part of the key
+            None => !inputs.is_empty(),
+            Some("true") => true,
+            Some("false") => false,
+            Some(x) => anyhow::bail!("expected `cache=true` or `cache=false`, got {x:?}"),
+        };
+        let ansi = match attributes.get("ansi") {
+            Some(x) => x.parse().map_err(Error::msg)?,
+            None => Ansi::default(),
+        };
+        let normalize = match attributes
+            .get("normalize")
+            .or_else(|| self.variables.get(normalize::VARIABLE))
+        {
+            Some(x) => normalize::parse(x)?,
+            None => self.settings.normalize.clone(),
+        };
+        Ok(Options {
+            timeout: timeout.filter(|x| !x.is_zero()),
+            cache,
+            inputs,
+            ansi,
+            normalize,
+        })
     }
 
     /// Execute or read to get the data
-    fn get_data(
-        &self,
+    fn get_data<'d>(
+        &mut self,
         in_type: InType,
-        data_line: Option<&'a str>,
-        data: Option<&'a str>,
-    ) -> Result<Box<dyn Read + 'a>> {
+        data_line: Option<&'d str>,
+        data: Option<&'d str>,
+        lang: Option<&str>,
+        options: &Options,
+    ) -> Result<Box<dyn Read + 'd>> {
         match in_type {
             InType::RawData => Ok(match (data_line, data) {
                 (Some(data_line), None) => {
@@ -113,118 +723,408 @@ impl<'a, W: Write> TheProcessor<'a, W> {
                 .chain(data.map(str::lines).into_iter().flatten())
                 .try_fold(Box::new(std::io::empty()) as Box<dyn Read>, |s, x| {
                     eprintln!("< {x}");
-                    Ok::<Box<dyn Read>, Error>(Box::new(s.chain(File::open(x)?)))
+                    self.confine("include", Path::new(include::path(x)))?;
+                    Ok::<Box<dyn Read>, Error>(Box::new(s.chain(include::open(x)?)))
                 }),
             InType::Execute => {
-                if let Some(data) = data_line.and(data) {
-                    let mut lines: usize = 0;
-                    for line in data.lines() {
-                        eprintln!("$ {line}");
-                        lines += 1;
-                        if lines > 4 {
-                            eprintln!("$ ...");
-                            break;
-                        }
-                    }
-                } else if let Some(data_line) = data_line {
-                    eprintln!("$ {data_line}");
+                self.confine("work dir", Path::new(self.workdir))?;
+                let cache = self.settings.cache.clone().filter(|_| {
+                    // skipping a command would change the state of the session
+                    options.cache && !self.settings.session
+                });
+                let Some(cache) = cache else {
+                    return self.execute(data_line, data, lang, options.timeout);
+                };
+                let key = self
+                    .cache_key(data_line, data, lang, &options.inputs)
+                    .context("computing cache key")?;
+                if let Some(output) = cache.get(&key)? {
+                    let code = data_line.or(data).unwrap_or_default();
+                    eprintln!("$ {} (cached)", code.lines().next().unwrap_or_default());
+                    return Ok(Box::new(Cursor::new(output)));
                 }
+                let mut output = Vec::with_capacity(8192);
+                self.execute(data_line, data, lang, options.timeout)?
+                    .read_to_end(&mut output)?;
+                // secrets don't get to the disk either
+                let output = self.secrets()?.mask(&output);
+                cache.put(&key, &output)?;
+                Ok(Box::new(Cursor::new(output)))
+            }
+        }
+    }
 
-                let oneliner = data_line.map(|command| format!("set -euo pipefail && {command}"));
+    /// Fails unless `path` is in the project root, if there is one.
+    fn confine(&self, what: &str, path: &Path) -> Result<()> {
+        match &self.root {
+            Some(root) => root::confine(root, what, path),
+            None => Ok(()),
+        }
+    }
 
-                let mut cmd = process::Command::new("bash");
+    /// Everything that the output of a command depends on,
+    /// as far as mdsh knows.
+    fn cache_key(
+        &self,
+        data_line: Option<&str>,
+        data: Option<&str>,
+        lang: Option<&str>,
+        inputs: &[String],
+    ) -> Result<Key> {
+        let mut key = KeyBuilder::default();
+        key.add("workdir", self.workdir.as_encoded_bytes())
+            .add("shell", &self.settings.shell.command)
+            .add("prologue", &self.settings.shell.prologue);
+        if self.settings.sandbox.is_some() {
+            key.add("sandbox", "");
+        }
+        if let Some(env) = &self.settings.env {
+            for (k, v) in &env.variables {
+                key.add("environment", k).add("value", v);
+            }
+        }
+        if let Some(lang) = lang {
+            let interpreter = self.settings.interpreters.0.get(lang);
+            key.add("lang", lang)
+                .add("interpreter", interpreter.map_or("", String::as_str));
+        }
+        if let Some(data_line) = data_line {
+            key.add("command", data_line);
+        }
+        if let Some(data) = data {
+            key.add("data", data);
+        }
+        for (k, v) in &self.variables {
+            key.add("variable", k).add("value", v);
+        }
+        for path in inputs {
+            key.add_file(Path::new(self.workdir), path)?;
+        }
+        Ok(key.finish())
+    }
 
-                if let Some(command) = oneliner {
-                    cmd.args(["-c", &command]);
+    /// Run a command or a script
+    fn execute<'d>(
+        &mut self,
+        data_line: Option<&'d str>,
+        data: Option<&'d str>,
+        lang: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<Box<dyn Read + 'd>> {
+        if let Some(data) = data_line.and(data) {
+            let mut lines: usize = 0;
+            for line in data.lines() {
+                eprintln!("$ {line}");
+                lines += 1;
+                if lines > 4 {
+                    eprintln!("$ ...");
+                    break;
                 }
+            }
+        } else if let Some(data_line) = data_line {
+            eprintln!("$ {data_line}");
+        }
 
-                let mut child = cmd
-                    .envs(&self.variables)
-                    .stdin(data.map_or_else(Stdio::null, |_| Stdio::piped()))
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::inherit())
-                    .current_dir(self.workdir)
-                    .spawn()?;
-
-                if let Some(data) = data {
-                    let mut stdin = child
-                        .stdin
-                        .take()
-                        .context("child process didn't provide stdin pipe")?;
-                    stdin
-                        .write_all(data.as_bytes())
-                        .context("writing to command's stdin")?;
-                    stdin.flush()?;
-                }
-                Ok(Box::new(Child(child)) as Box<dyn Read>)
+        let message = |timeout| {
+            let command = data_line.unwrap_or("script");
+            format!("`{command}` timed out after {timeout:?}")
+        };
+
+        if self.settings.session {
+            let shell_code = match (data_line, data) {
+                (Some(command), data) => Some((command, data)),
+                (None, Some(script)) => self
+                    .settings
+                    .interpreters
+                    .command(lang, script)
+                    .is_none()
+                    .then_some((script, None)),
+                (None, None) => None,
+            };
+            if let Some((code, data)) = shell_code {
+                let session = match &mut self.session {
+                    Some(session) => session,
+                    session => session.insert(
+                        Session::spawn(
+                            &self.settings.shell,
+                            self.workdir,
+                            self.settings.env.as_ref(),
+                            &self.variables,
+                            self.settings.sandbox.as_ref(),
+                        )
+                        .context("starting the shell session")?,
+                    ),
+                };
+                let timeout = timeout.map(|x| (x, message(x)));
+                let output = session.run(code, data, &self.variables, timeout)?;
+                return Ok(Box::new(Cursor::new(output)));
             }
         }
+
+        let (mut cmd, stdin) = match (data_line, data) {
+            (Some(command), data) => {
+                let mut cmd = self.settings.shell.command()?;
+                cmd.arg("-c").arg(&*self.settings.shell.script(command));
+                (cmd, data.map(Cow::from))
+            }
+            (None, Some(script)) => match self.settings.interpreters.command(lang, script) {
+                Some(cmd) => (cmd, Some(script.into())),
+                None => (
+                    self.settings.shell.command()?,
+                    Some(self.settings.shell.script(script)),
+                ),
+            },
+            (None, None) => (self.settings.shell.command()?, None),
+        };
+
+        if let Some(sandbox) = &self.settings.sandbox {
+            sandbox.apply(&mut cmd, Path::new(self.workdir))?;
+        }
+        if timeout.is_some() {
+            // so that the whole pipeline can be killed at once
+            #[cfg(unix)]
+            std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        }
+        if let Some(env) = &self.settings.env {
+            env.apply(&mut cmd);
+        }
+
+        let mut child = cmd
+            .envs(&self.variables)
+            .stdin(stdin.as_ref().map_or_else(Stdio::null, |_| Stdio::piped()))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .current_dir(self.workdir)
+            .spawn()
+            .with_context(|| format!("failed to run {:?}", cmd.get_program()))?;
+        let stderr = child.stderr.take().map(StderrTail::new);
+
+        let watchdog = timeout.map(|x| Watchdog::new(child.id(), x, message(x)));
+        if let Some(stdin_data) = stdin {
+            let mut stdin = child
+                .stdin
+                .take()
+                .context("child process didn't provide stdin pipe")?;
+            stdin
+                .write_all(stdin_data.as_bytes())
+                .context("writing to command's stdin")?;
+            stdin.flush()?;
+        }
+        Ok(Box::new(Child(child, watchdog, stderr)) as Box<dyn Read>)
     }
 
-    /// Takes data and acts on it
-    fn act_on_data<R: Read>(&mut self, out_type: OutType<'a>, data: &mut R) -> Result<()> {
+    /// Takes data and acts on it, returns the block to write
+    fn act_on_data<R: Read>(
+        &mut self,
+        out_type: OutType<'a>,
+        data: &mut R,
+        ansi: Ansi,
+        first_line: Option<usize>,
+    ) -> Result<Vec<u8>> {
+        let mut block = Vec::with_capacity(8192);
+        let prefix = self.prefix.as_str();
         match out_type {
-            OutType::Markdown => produce_fenced_block(data, &mut self.out),
-            OutType::Environment => self.env_var_list(data),
-            OutType::CodeBlock(lang_name) => produce_code_block(lang_name, data, &mut self.out),
+            OutType::Environment => self.env_var_list(data, first_line),
+            _ if ansi == Ansi::Html => {
+                produce_html_block(data, &mut Prefixed::new(&mut block, prefix))
+            }
+            OutType::Markdown => produce_fenced_block(
+                &mut filter_ansi(data, ansi)?,
+                &mut Prefixed::new(&mut block, prefix),
+            ),
+            OutType::CodeBlock(lang_name) => produce_code_block(
+                &lang_name,
+                &mut filter_ansi(data, ansi)?,
+                &mut Prefixed::new(&mut block, prefix),
+            ),
         }
-        .context("acting on data")
+        .context("acting on data")?;
+        Ok(block)
     }
 
-    pub fn env_var_list<R: Read>(&mut self, data: &mut R) -> Result<()> {
-        use std::borrow::Cow;
+    /// Defines the variables in `data`, which starts at `first_line` of
+    /// the document if it is written there.
+    pub fn env_var_list<R: Read>(&mut self, data: &mut R, first_line: Option<usize>) -> Result<()> {
+        use std::{borrow::Cow, env::VarError};
 
         use nom::Finish;
 
-        use crate::parser::{env_var_line, fmt_nom_error};
+        use nom::Offset as _;
+        use nom_language::error::VerboseErrorKind;
+
+        use crate::parser::env_var_line;
 
         let mut input = String::with_capacity(8192);
         data.read_to_string(&mut input)?;
         let input = input.as_str();
 
         let mut iter = nom::combinator::iterator(input, env_var_line());
-        for (k, v) in iter.by_ref().flatten() {
+        for (secret, k, v) in iter.by_ref().flatten() {
             let val = shellexpand::env_with_context(v, |x| {
-                self.variables
-                    .get(x)
-                    .map_or_else(|| std::env::var(x).map(Cow::from), |x| Ok(Cow::from(x)))
-                    .map(Some)
+                if let Some(x) = self.variables.get(x) {
+                    return Ok(Some(Cow::from(x)));
+                }
+                match &self.settings.env {
+                    Some(env) => env.get(x).map(Cow::from).ok_or(VarError::NotPresent),
+                    None => std::env::var(x).map(Cow::from),
+                }
+                .map(Some)
             })
             .context("expanding shell variables")?
             .into();
 
-            eprintln!("! {k}='{val}'");
-            self.variables.insert(k.to_owned(), val);
+            if secret {
+                eprintln!("! secret {k}='{}'", secret::MASK);
+                if !self.secrets.iter().any(|x| x == k) {
+                    self.secrets.push(k.to_owned());
+                }
+                self.variables.insert(k.to_owned(), val);
+            } else {
+                self.variables.insert(k.to_owned(), val);
+                // it may hold a secret, or be one by its name
+                eprintln!("! {k}='{}'", self.secrets()?.mask_str(&self.variables[k]));
+            }
         }
-        // TODO: error msg with absolute line numbers
-        iter.finish()
-            .finish()
-            .map_err(fmt_nom_error(input, "`!` env block"))?;
-        Ok(())
+        let Err(e) = iter.finish().finish() else {
+            return Ok(());
+        };
+        let (rest, kind) = &e.errors[0];
+        let (line, column) = crate::position(input, input.offset(rest));
+        let text = input.lines().nth(line - 1).unwrap_or_default();
+        let expected = match kind {
+            VerboseErrorKind::Char(c) => format!("expected `{c}`"),
+            VerboseErrorKind::Context(x) => format!("expected {x}"),
+            VerboseErrorKind::Nom(x) => format!("{x:?}"),
+        };
+        let (location, line) = match first_line {
+            Some(first_line) => {
+                let line = first_line + line - 1;
+                (format!("{}:{line}:{column}", self.file), line)
+            }
+            None => (format!("line {line} of the `!` data"), line),
+        };
+        let snippet = crate::snippet(line, text, Some(column));
+        anyhow::bail!("{location}: {expected}\n{snippet}")
     }
 }
 
+/// Removes escape sequences, unless they are to be kept.
+fn filter_ansi<R: Read>(r: &mut R, ansi: Ansi) -> Result<Cursor<Vec<u8>>> {
+    let mut data = Vec::with_capacity(8192);
+    r.read_to_end(&mut data)?;
+    if ansi == Ansi::Strip {
+        data = ansi::strip(&data);
+    }
+    Ok(Cursor::new(data))
+}
+
 fn produce_fenced_block<R: Read, W: Write>(r: &mut R, w: &mut W) -> Result<()> {
-    writeln!(w, "\n{BEGIN_MDSH}")?;
-    std::io::copy(r, w)?;
-    writeln!(w, "{END_MDSH}")?;
+    let mut data = Vec::with_capacity(8192);
+    r.read_to_end(&mut data)?;
+    if !data.is_empty() && !data.ends_with(b"\n") {
+        data.push(b'\n');
+    }
+    let (begin, end) = markers(&data);
+    writeln!(w, "\n{begin}")?;
+    w.write_all(&data)?;
+    writeln!(w, "{end}")?;
     Ok(())
 }
 
+/// Markers around generated output. Output containing markers itself
+/// gets numbered ones, with a number that it doesn't use.
+fn markers(data: &[u8]) -> (Cow<'static, str>, Cow<'static, str>) {
+    if !contains(data, b"<!-- BEGIN mdsh") && !contains(data, b"<!-- END mdsh") {
+        return (BEGIN_MDSH.into(), END_MDSH.into());
+    }
+    let mut id = 1;
+    while contains(data, format!("<!-- END mdsh {id} -->").as_bytes()) {
+        id += 1;
+    }
+    (
+        format!("<!-- BEGIN mdsh {id} -->").into(),
+        format!("<!-- END mdsh {id} -->").into(),
+    )
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|x| x == needle)
+}
+
 fn produce_code_block<R: Read, W: Write>(lang: &str, r: &mut R, w: &mut W) -> Result<()> {
+    let mut data = Vec::with_capacity(8192);
+    r.read_to_end(&mut data)?;
+    if !data.is_empty() && !data.ends_with(b"\n") {
+        data.push(b'\n');
+    }
+    // the fence has to be longer than any backtick run in the code
+    let longest_run = data.split(|x| *x != b'`').map(<[u8]>::len).max();
+    let fence = "`".repeat(longest_run.unwrap_or_default().max(2) + 1);
     produce_fenced_block(
-        &mut format!("```{lang}\n")
+        &mut format!("{fence}{lang}\n")
             .as_bytes()
-            .chain(r)
-            .chain("```\n".as_bytes()),
+            .chain(&data[..])
+            .chain(format!("{fence}\n").as_bytes()),
         w,
     )
 }
 
+/// Output with its colors, in a `<pre>` block rather than a code block,
+/// which can't have any markup.
+fn produce_html_block<R: Read, W: Write>(r: &mut R, w: &mut W) -> Result<()> {
+    let mut data = Vec::with_capacity(8192);
+    r.read_to_end(&mut data)?;
+    let mut html = ansi::to_html(&data);
+    if !html.is_empty() && !html.ends_with('\n') {
+        html.push('\n');
+    }
+    produce_fenced_block(&mut format!("<pre>\n{html}</pre>\n").as_bytes(), w)
+}
+
+/// Writer that starts every line with the prefix of the enclosing
+/// containers, so that generated blocks stay inside list items and
+/// blockquotes. Blank lines get the prefix without trailing whitespace.
+struct Prefixed<'p, W> {
+    out: W,
+    prefix: &'p str,
+    at_line_start: bool,
+}
+
+impl<'p, W> Prefixed<'p, W> {
+    fn new(out: W, prefix: &'p str) -> Self {
+        Self {
+            out,
+            prefix,
+            at_line_start: true,
+        }
+    }
+}
+
+impl<W: Write> Write for Prefixed<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for line in buf.split_inclusive(|x| *x == b'\n') {
+            if self.at_line_start {
+                let prefix = if line == b"\n" {
+                    self.prefix.trim_end()
+                } else {
+                    self.prefix
+                };
+                self.out.write_all(prefix.as_bytes())?;
+            }
+            self.out.write_all(line)?;
+            self.at_line_start = line.ends_with(b"\n");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
 /// Helper wrapper over [`std::process::Child`] that calls
 /// [`std::process::Child::wait`] when [`Read::read`] returns 0.
-struct Child(std::process::Child);
+struct Child(std::process::Child, Option<Watchdog>, Option<StderrTail>);
 
 impl Read for Child {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
@@ -237,10 +1137,137 @@ impl Read for Child {
             .read(buf)?;
         if n == 0 {
             let res = self.0.wait()?;
+            let stderr = self.2.take().map(StderrTail::finish).unwrap_or_default();
+            if let Some(message) = self.1.take().and_then(Watchdog::cancel) {
+                return Err(Error::other(message));
+            }
             if !res.success() {
-                return Err(Error::other(format!("Child process terminated with {res}")));
+                return Err(Error::other(CommandFailed {
+                    status: res.to_string(),
+                    code: res.code(),
+                    stderr,
+                }));
             }
         }
         Ok(n)
     }
 }
+
+/// A command that didn't exit successfully.
+#[derive(Debug)]
+pub struct CommandFailed {
+    /// Like `exit status: 1`.
+    pub status: String,
+    /// Exit code, unless a signal killed it.
+    pub code: Option<i32>,
+    /// The last lines that it wrote to stderr.
+    pub stderr: Vec<String>,
+}
+
+impl CommandFailed {
+    /// The failed command that caused `error`, if any.
+    pub fn find(error: &anyhow::Error) -> Option<&Self> {
+        error.chain().find_map(|x| {
+            x.downcast_ref::<Self>().or_else(|| {
+                x.downcast_ref::<std::io::Error>()?
+                    .get_ref()?
+                    .downcast_ref::<Self>()
+            })
+        })
+    }
+}
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "command failed with {}", self.status)?;
+        if !self.stderr.is_empty() {
+            write!(f, ", its stderr ended with:")?;
+            for line in &self.stderr {
+                write!(f, "\n    {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Thread that passes the stderr of a command on to mdsh's as it comes,
+/// and keeps its last lines for error messages.
+pub(crate) struct StderrTail(thread::JoinHandle<Vec<String>>);
+
+impl StderrTail {
+    const LINES: usize = 5;
+
+    fn new(mut stderr: ChildStderr) -> Self {
+        Self(thread::spawn(move || {
+            let mut tail = Vec::new();
+            let mut buf = [0; 8192];
+            while let Ok(n @ 1..) = stderr.read(&mut buf) {
+                let _ = std::io::stderr().write_all(&buf[..n]);
+                tail.extend_from_slice(&buf[..n]);
+                // enough for the last lines, unless they are very long
+                if tail.len() > 2 * buf.len() {
+                    tail.drain(..tail.len() - buf.len());
+                }
+            }
+            Self::lines(&tail)
+        }))
+    }
+
+    /// The last non-empty lines of `tail`.
+    pub(crate) fn lines(tail: &[u8]) -> Vec<String> {
+        let tail = String::from_utf8_lossy(tail);
+        let lines: Vec<&str> = tail.lines().filter(|x| !x.trim().is_empty()).collect();
+        lines[lines.len().saturating_sub(Self::LINES)..]
+            .iter()
+            .map(|x| (*x).to_owned())
+            .collect()
+    }
+
+    fn finish(self) -> Vec<String> {
+        self.0.join().unwrap_or_default()
+    }
+}
+
+/// Thread that kills a command, along with its process group,
+/// once its time is up.
+pub(crate) struct Watchdog {
+    cancel: mpsc::Sender<()>,
+    thread: thread::JoinHandle<Option<String>>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(pid: u32, timeout: Duration, message: String) -> Self {
+        let (cancel, cancelled) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let Err(mpsc::RecvTimeoutError::Timeout) = cancelled.recv_timeout(timeout) else {
+                return None;
+            };
+            kill_process_group(pid);
+            Some(message)
+        });
+        Self { cancel, thread }
+    }
+
+    /// Stops the watchdog, returns its message if it had to kill the command.
+    pub(crate) fn cancel(self) -> Option<String> {
+        let _ = self.cancel.send(());
+        self.thread.join().ok().flatten()
+    }
+}
+
+#[cfg(unix)]
+fn kill_process_group(pid: u32) {
+    // SAFETY: only sends a signal, the group is the one the command leads
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(pid: u32) {
+    let _ = process::Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .output();
+}
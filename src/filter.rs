@@ -0,0 +1,95 @@
+//! Filters that transform the output of a command or include line by line,
+//! like `|> head=20 |> grep=/^error/`, the same on every machine.
+use anyhow::Result;
+use nom::{combinator::all_consuming, Parser as _};
+use regex::Regex;
+
+use crate::parser::{filters, fmt_nom_error};
+
+#[derive(Debug, Clone)]
+pub enum Filter {
+    /// `head=N`, the first N lines.
+    Head(usize),
+    /// `tail=N`, the last N lines.
+    Tail(usize),
+    /// `grep=/regex/`, the lines that match.
+    Grep(Regex),
+    /// `replace=/regex/replacement/`, with `$1` for capture groups,
+    /// on every line.
+    Replace(Regex, String),
+    /// `dedent`, without the leading whitespace that all lines share.
+    Dedent,
+    /// `sort`, the lines in byte order.
+    Sort,
+}
+
+/// Splits the filters off the end of `data_line`, if it has any.
+/// The first `|>` where only filters follow starts them, so shell pipes,
+/// even into commands like `sort`, stay with the command.
+pub fn split(data_line: &str) -> Result<(&str, Vec<Filter>)> {
+    for (at, _) in data_line.match_indices("|>") {
+        let tail = &data_line[at..];
+        match all_consuming(filters()).parse(tail) {
+            Ok((_, filters)) => return Ok((data_line[..at].trim_end(), filters)),
+            // past something like `grep=`, so it's a filter, but invalid
+            Err(nom::Err::Failure(e)) => return Err(fmt_nom_error(tail, "filters")(e)),
+            Err(_) => (),
+        }
+    }
+    Ok((data_line, Vec::new()))
+}
+
+/// Applies `filters` in order.
+pub fn apply(filters: &[Filter], data: &[u8]) -> Vec<u8> {
+    let data = String::from_utf8_lossy(data);
+    let mut lines: Vec<String> = data.lines().map(str::to_owned).collect();
+    for filter in filters {
+        match filter {
+            Filter::Head(n) => lines.truncate(*n),
+            Filter::Tail(n) => {
+                lines.drain(..lines.len().saturating_sub(*n));
+            }
+            Filter::Grep(regex) => lines.retain(|x| regex.is_match(x)),
+            Filter::Replace(regex, replacement) => {
+                for line in &mut lines {
+                    *line = regex.replace_all(line, replacement.as_str()).into_owned();
+                }
+            }
+            Filter::Dedent => {
+                let indent = |x: &str| x.len() - x.trim_start_matches([' ', '\t']).len();
+                let common = lines
+                    .iter()
+                    .filter(|x| indent(x) < x.len())
+                    .map(|x| indent(x))
+                    .min()
+                    .unwrap_or_default();
+                for line in &mut lines {
+                    line.drain(..indent(line).min(common));
+                }
+            }
+            Filter::Sort => lines.sort(),
+        }
+    }
+    let mut out = lines.join("\n").into_bytes();
+    if !out.is_empty() {
+        out.push(b'\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let (command, filters) = split("ls | sort -u |> head=1 |>grep=/a|>b/").unwrap();
+        assert_eq!((command, filters.len()), ("ls | sort -u", 2));
+        let (command, filters) = split("ls | sort | head -1").unwrap();
+        assert_eq!((command, filters.len()), ("ls | sort | head -1", 0));
+        let (command, filters) = split("echo '|> sort' |> sorted").unwrap();
+        assert_eq!((command, filters.len()), ("echo '|> sort' |> sorted", 0));
+        assert!(split("ls |> grep=/(/").is_err());
+        assert!(split("ls |> head=ten").is_err());
+    }
+}
@@ -53,9 +53,135 @@ fn main() {
             }
         }
     }
+    for (section_title, subsections) in [
+        (
+            "Markdown structure",
+            &[
+                ("Ignored commands", IGNORED_CASES),
+                ("Recognized commands", RECOGNIZED_CASES),
+                ("Commands in containers", CONTAINER_CASES),
+                ("Output containing markup", MARKUP_OUTPUT_CASES),
+            ][..],
+        ),
+        (
+            "Including parts of files",
+            &[
+                ("Selecting lines and regions", SELECTOR_CASES),
+                ("Querying structured files", QUERY_CASES),
+            ][..],
+        ),
+        (
+            "Executing other languages",
+            &[("Picking the interpreter", INTERPRETER_CASES)][..],
+        ),
+        (
+            "Action attributes",
+            &[
+                ("Setting a timeout", TIMEOUT_CASES),
+                ("Handling colors", ANSI_CASES),
+                ("Normalizing volatile output", NORMALIZE_CASES),
+            ][..],
+        ),
+        (
+            "Filtering output",
+            &[
+                ("Filtering command output", FILTER_CASES),
+                ("Filtering included files", INCLUDE_FILTER_CASES),
+            ][..],
+        ),
+        (
+            "Masking secrets",
+            &[("Masking variables and patterns", SECRET_CASES)][..],
+        ),
+    ] {
+        println!("## {section_title}\n");
+        for (title, cases) in subsections {
+            println!("### {title}\n");
+            for (name, case) in *cases {
+                println!("#### {name}\n");
+                println!("{case}\n");
+            }
+        }
+    }
     println!("The end!");
 }
 
+/// Things that look like commands, but aren't where markdown sees them.
+const IGNORED_CASES: &[(&str, &str)] = &[
+    (
+        "Ignoring command in indented code block",
+        "    `> $ echo 'I am not a command'`",
+    ),
+    (
+        "Ignoring command in code block with a longer fence",
+        "````md\n```sh > $\necho 'I am not a command'\n```\n````",
+    ),
+    (
+        "Ignoring command in tilde code block",
+        "~~~html\n<!-- > $ echo 'I am not a command' -->\n~~~",
+    ),
+    (
+        "Ignoring command in setext heading",
+        "`> $ echo 'I am not a command'`\n---",
+    ),
+    (
+        "Ignoring generated block markers in code block",
+        "```md\n<!-- BEGIN mdsh -->\nnot generated\n<!-- END mdsh -->\n```",
+    ),
+];
+
+/// Commands next to other blocks that don't swallow them.
+const RECOGNIZED_CASES: &[(&str, &str)] = &[
+    (
+        "Executing command after thematic break",
+        "***\n`> $ echo 'I am *markdown*'`",
+    ),
+    (
+        "Executing command after HTML comment",
+        "<!-- not a command -->\n`> $ echo 'I am *markdown*'`",
+    ),
+    (
+        "Executing command in indented fenced code block",
+        "   ```sh > $\n   echo 'I am *markdown*'\n   ```",
+    ),
+    (
+        "Executing command followed by list",
+        "`> $ echo 'I am *markdown*'`\n- a list item",
+    ),
+    (
+        "Executing command continuing a paragraph",
+        "Run this:\n`> $ echo 'I am *markdown*'`",
+    ),
+    (
+        "Executing command after blockquote line",
+        "> Quoted text\n`> $ echo 'I am *markdown*'`",
+    ),
+    (
+        "Executing command in HTML block",
+        "<details>\n<summary>Output</summary>\n`> $ echo 'I am *markdown*'`\n</details>",
+    ),
+];
+
+/// Commands nested in list items and blockquotes.
+const CONTAINER_CASES: &[(&str, &str)] = &[
+    (
+        "Executing command in list item",
+        "1. First step:\n\n   ```sh > $\n   echo 'I am *markdown*'\n   ```\n\n2. Second step",
+    ),
+    (
+        "Executing command in tight list",
+        "- `> yaml $ echo 'foo: true'`\n- another item",
+    ),
+    (
+        "Executing command in blockquote",
+        "> Quoted text\n>\n> <!-- > $ echo 'I am *markdown*' -->",
+    ),
+    (
+        "Executing command in blockquote in list item",
+        "- Nested:\n  > ```sh > $\n  > echo 'I am *markdown*'\n  > ```",
+    ),
+];
+
 #[derive(Debug, Eq, PartialEq)]
 enum Container {
     Code(ContainerType),
@@ -241,3 +367,190 @@ const fn mk_out_cmd(x: &OutCmd) -> &'static str {
         EnvVars => "!",
     }
 }
+
+/// Output that would end the generated block too early if it were
+/// fenced naively.
+const MARKUP_OUTPUT_CASES: &[(&str, &str)] = &[
+    (
+        "Producing code block from output with backticks",
+        "```sh > md $\nprintf '%s\\n' '```sh' 'echo hi' '```'\n```",
+    ),
+    (
+        "Producing raw markdown from output with markers",
+        "```sh > $\nprintf '%s\\n' '<!-- BEGIN mdsh -->' 'included' '<!-- END mdsh -->'\n```",
+    ),
+    (
+        "Producing raw markdown from output with numbered markers",
+        "```sh > $\nprintf '%s\\n' '<!-- BEGIN mdsh 1 -->' 'included' '<!-- END mdsh 1 -->'\n```",
+    ),
+];
+
+/// File includes narrowed down with `#` selectors.
+const SELECTOR_CASES: &[(&str, &str)] = &[
+    (
+        "Reading line range",
+        "`> rust < ./samples/anchors.rs#L3-L5`",
+    ),
+    ("Reading single line", "`> rust < ./samples/anchors.rs#L4`"),
+    ("Reading region", "`> rust < ./samples/anchors.rs#greeting`"),
+    (
+        "Reading region with nested anchors",
+        "[> rust < the whole function](./samples/anchors.rs#main)",
+    ),
+];
+
+/// JSON pointers into TOML, JSON and YAML includes.
+const QUERY_CASES: &[(&str, &str)] = &[
+    (
+        "Querying TOML scalar",
+        "`> < ./samples/example.toml#/package/version`",
+    ),
+    (
+        "Querying TOML table",
+        "`> toml < ./samples/example.toml#/dependencies`",
+    ),
+    (
+        "Querying JSON array element",
+        "`> < ./samples/example.json#/files/1`",
+    ),
+    (
+        "Querying JSON object",
+        "`> json < ./samples/example.json#/scripts`",
+    ),
+    ("Querying YAML scalar", "`> < ./samples/example.yml#/foo`"),
+];
+
+/// Code blocks that run with something else than bash.
+const INTERPRETER_CASES: &[(&str, &str)] = &[
+    (
+        "Executing Python code block",
+        "```python > $\nprint(6 * 7)\n```",
+    ),
+    (
+        "Executing Python code block into code block",
+        "```py > json $\nimport json\nprint(json.dumps({'answer': 42}))\n```",
+    ),
+    (
+        "Executing code block with shebang",
+        "```text > $\n#!/usr/bin/env perl\nprint \"from perl\\n\";\n```",
+    ),
+    (
+        "Executing shell code block",
+        "```sh > $\necho \"from $0\"\n```",
+    ),
+    (
+        "Executing shell code block in strict mode",
+        "```sh > $\n[[ -o errexit && -o nounset && -o pipefail ]] && echo strict\n```",
+    ),
+    (
+        "Executing command in strict mode",
+        "`> $ [[ -o errexit && -o nounset && -o pipefail ]] && echo strict`",
+    ),
+];
+
+/// `{timeout=...}` on commands that finish in time.
+const TIMEOUT_CASES: &[(&str, &str)] = &[
+    (
+        "Command with timeout",
+        "`> $ {timeout=10s} echo 'finished in time'`",
+    ),
+    (
+        "Code block with timeout",
+        "```sh > $ {timeout=1m}\necho 'finished in time'\n```",
+    ),
+    (
+        "Command without timeout",
+        "`> $ {timeout=0} echo 'no time limit'`",
+    ),
+];
+
+const ANSI_CASES: &[(&str, &str)] = &[
+    (
+        "Stripping colors from markdown",
+        "`> $ printf '\\033[1;31merror\\033[0m: <none>\\n'`",
+    ),
+    (
+        "Stripping colors from a code block",
+        "`> txt $ printf '\\033[32mok\\033[0m\\n'`",
+    ),
+    (
+        "Rendering colors as HTML",
+        "`> $ {ansi=html} printf '\\033[1;31merror\\033[0m: <none>\\n'`",
+    ),
+];
+
+const NORMALIZE_CASES: &[(&str, &str)] = &[
+    (
+        "Normalizing with built-in rules",
+        "`> $ {normalize=dates,durations} echo 'built 2024-03-01T10:20:30Z in 0.32s'`",
+    ),
+    (
+        "Normalizing paths and hashes",
+        "`> $ {normalize=tmp,hashes} echo 'wrote /tmp/tmp.Xy3/out.txt at 3fa2c91, 1234567 bytes'`",
+    ),
+    (
+        "Normalizing with a custom rule",
+        "`> $ {normalize=/pid\\s[0-9]+/<pid>/} echo 'started pid 4242'`",
+    ),
+    (
+        "Normalizing the whole document",
+        "```!\nMDSH_NORMALIZE=\"durations /pid [0-9]+/pid <pid>/\"\n```\n\n\
+         `> $ echo 'pid 4242 finished in 1m30s'`\n\n\
+         `> $ {normalize=none} echo 'pid 4242 finished in 1m30s'`",
+    ),
+];
+
+const SECRET_CASES: &[(&str, &str)] = &[
+    (
+        "Masking a secret variable",
+        "`! secret TOKEN=hunter22`\n\n`> $ echo \"token: $TOKEN\"`",
+    ),
+    (
+        "Masking variables and patterns of the document",
+        "```!\nMDSH_SECRETS=\"SERVICE_* /[a-z0-9-]+[.]corp[.]example[.]com/\"\nSERVICE_KEY=abc123\n```\n\n\
+         `> $ echo \"key $SERVICE_KEY for db-1.corp.example.com\"`",
+    ),
+];
+
+const FILTER_CASES: &[(&str, &str)] = &[
+    (
+        "Keeping the first lines",
+        "`> $ printf 'one\\ntwo\\nthree\\n' |> head=2`",
+    ),
+    (
+        "Keeping the last lines",
+        "`> $ printf 'one\\ntwo\\nthree\\n' |> tail=1`",
+    ),
+    (
+        "Keeping matching lines",
+        "`> $ printf 'error: one\\nwarning: two\\nerror: three\\n' |> grep=/^error/`",
+    ),
+    (
+        "Replacing in every line",
+        "`> $ echo 'saved to /home/alice/notes' |> replace=/\\/home\\/\\w+/~/`",
+    ),
+    (
+        "Replacing with capture groups",
+        "`> $ echo 'version 1.2.3' |> replace=/(\\d+)\\.(\\d+)\\.\\d+/$1.$2.x/`",
+    ),
+    ("Sorting lines", "`> $ printf 'b\\nc\\na\\n' |> sort`"),
+    (
+        "Chaining filters after a shell pipe",
+        "`> $ printf 'b 2\\nc 3\\na 1\\n' | cut -d' ' -f1 | sort |> head=2`",
+    ),
+    (
+        "Filtering the output of a script",
+        "```> sh $ |> grep=/o/\necho one\necho two\necho three\n```",
+    ),
+];
+
+const INCLUDE_FILTER_CASES: &[(&str, &str)] = &[
+    (
+        "Dedenting a region",
+        "`> rust < ./samples/anchors.rs#greeting |> dedent`",
+    ),
+    (
+        "Leaving out lines",
+        "`> rust < ./samples/anchors.rs |> grep=/^[^\\/]/`",
+    ),
+];
@@ -0,0 +1,183 @@
+//! Partial file includes: `< path#L10-L42`, `< path#region` and
+//! `< path#/json/pointer`.
+use std::{
+    fs::File,
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use anyhow::{ensure, Context, Result};
+use nom::{combinator::all_consuming, Finish, Parser as _};
+
+use crate::parser::{fmt_nom_error, include_selector};
+
+/// What to take out of an included file, given after its `#`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selector<'a> {
+    /// `#L10` or `#L10-L42`, 1-based and inclusive.
+    Lines { start: usize, end: usize },
+    /// `#name`, the lines between `ANCHOR: name` and `ANCHOR_END: name`
+    /// markers, as in mdBook.
+    Region(&'a str),
+    /// `#/package/version`, a JSON pointer into a TOML, JSON or YAML file.
+    Query(&'a str),
+}
+
+/// The file of `spec`, without its selector.
+pub fn path(spec: &str) -> &str {
+    spec.split_once('#').map_or(spec, |(path, _)| path)
+}
+
+/// Opens `path`, or the selected part of it if `spec` is `path#selector`.
+pub fn open(spec: &str) -> Result<Box<dyn Read>> {
+    let Some((path, selector)) = spec.split_once('#') else {
+        return Ok(Box::new(
+            File::open(spec).with_context(|| format!("failed to open file {spec:?}"))?,
+        ));
+    };
+    let (_, selector) = all_consuming(include_selector())
+        .parse(selector)
+        .finish()
+        .map_err(fmt_nom_error(selector, "include selector"))?;
+
+    let mut content = String::with_capacity(8192);
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .with_context(|| format!("failed to read file {path:?}"))?;
+    let selected = selector
+        .select(path, &content)
+        .with_context(|| format!("selecting from file {path:?}"))?;
+    Ok(Box::new(Cursor::new(selected)))
+}
+
+impl Selector<'_> {
+    /// Selects from the `content` of the file at `path`, whose extension
+    /// tells the format for queries.
+    pub fn select(&self, path: &str, content: &str) -> Result<String> {
+        match *self {
+            Selector::Lines { start, end } => {
+                let lines: Vec<&str> = content.split_inclusive('\n').collect();
+                ensure!(
+                    0 < start && start <= end && end <= lines.len(),
+                    "lines L{start}-L{end} are out of range, the file has {} lines",
+                    lines.len()
+                );
+                Ok(lines[start - 1..end].concat())
+            }
+            Selector::Region(name) => {
+                let mut selected = None::<String>;
+                for line in content.split_inclusive('\n') {
+                    match (anchor(line), selected.as_mut()) {
+                        (Some(Anchor::Start(x)), None) if x == name => {
+                            selected = Some(String::new())
+                        }
+                        (Some(Anchor::End(x)), Some(_)) if x == name => break,
+                        // nested anchors don't show up in the output
+                        (Some(_), _) | (None, None) => (),
+                        (None, Some(selected)) => selected.push_str(line),
+                    }
+                }
+                selected.with_context(|| format!("no `ANCHOR: {name}` found"))
+            }
+            Selector::Query(pointer) => {
+                let extension = Path::new(path).extension().and_then(|x| x.to_str());
+                let mut selected = match extension {
+                    Some("toml") => query_toml(content, pointer)?,
+                    Some("json") => query_json(content, pointer)?,
+                    Some("yaml" | "yml") => query_yaml(content, pointer)?,
+                    _ => anyhow::bail!("can only query .toml, .json, .yaml and .yml files"),
+                };
+                if !selected.ends_with('\n') {
+                    selected.push('\n');
+                }
+                Ok(selected)
+            }
+        }
+    }
+}
+
+/// Scalars are included as is, subtrees are serialized back to TOML.
+fn query_toml(content: &str, pointer: &str) -> Result<String> {
+    use toml::Value;
+    let root = Value::Table(toml::from_str(content).context("parsing TOML")?);
+    let value = walk(&root, pointer, |value, key| match value {
+        Value::Table(table) => table.get(key),
+        Value::Array(array) => array.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })?;
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        Value::Table(table) => toml::to_string(table)?,
+        value => value.to_string(),
+    })
+}
+
+/// Scalars are included as is, subtrees are pretty-printed.
+fn query_json(content: &str, pointer: &str) -> Result<String> {
+    use serde_json::Value;
+    let root: Value = serde_json::from_str(content).context("parsing JSON")?;
+    let value = walk(&root, pointer, |value, key| match value {
+        Value::Object(object) => object.get(key),
+        Value::Array(array) => array.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })?;
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        value => serde_json::to_string_pretty(value)?,
+    })
+}
+
+/// Scalars are included as is, subtrees are serialized back to YAML.
+fn query_yaml(content: &str, pointer: &str) -> Result<String> {
+    use serde_yaml::Value;
+    let root: Value = serde_yaml::from_str(content).context("parsing YAML")?;
+    let value = walk(&root, pointer, |value, key| match value {
+        Value::Mapping(mapping) => mapping.get(key),
+        Value::Sequence(sequence) => sequence.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })?;
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        value => serde_yaml::to_string(value)?,
+    })
+}
+
+/// Follows a JSON pointer through a document, `get` looking up
+/// a single key or array index.
+fn walk<'v, V>(
+    root: &'v V,
+    pointer: &str,
+    get: impl Fn(&'v V, &str) -> Option<&'v V>,
+) -> Result<&'v V> {
+    let mut value = root;
+    let mut end = 0;
+    for token in pointer.split('/').skip(1) {
+        end += 1 + token.len();
+        let key = token.replace("~1", "/").replace("~0", "~");
+        value = get(value, &key).with_context(|| format!("nothing at `{}`", &pointer[..end]))?;
+    }
+    Ok(value)
+}
+
+enum Anchor<'a> {
+    Start(&'a str),
+    End(&'a str),
+}
+
+/// Finds an `ANCHOR: name` or `ANCHOR_END: name` marker, in whatever
+/// comment syntax the line uses.
+fn anchor(line: &str) -> Option<Anchor<'_>> {
+    fn name(rest: &str) -> &str {
+        let rest = rest.trim_start();
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(rest.len());
+        &rest[..end]
+    }
+    if let Some((_, rest)) = line.split_once("ANCHOR_END:") {
+        Some(Anchor::End(name(rest)))
+    } else {
+        let (_, rest) = line.split_once("ANCHOR:")?;
+        Some(Anchor::Start(name(rest)))
+    }
+}
@@ -0,0 +1,204 @@
+//! Inputs given as directories or globs, like `docs/` or `'**/*.md'`,
+//! expanded into the documents they have. Files that `.gitignore` or
+//! `.mdshignore` ignore, files that aren't text, and files without actions
+//! are left out.
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+
+use crate::{cli::FileArg, MdPiece, Processor};
+
+/// Like `.gitignore`, but only for mdsh.
+pub const IGNORE_FILE: &str = ".mdshignore";
+
+/// Extensions of the documents that directories are searched for.
+const EXTENSIONS: &[&str] = &["md", "markdown"];
+
+/// Finds out whether a document has any action.
+#[derive(Default)]
+struct Actions(bool);
+
+impl<'a> Processor<'a> for Actions {
+    fn process_piece(&mut self, piece: MdPiece<'a>) -> Result<()> {
+        match piece {
+            MdPiece::Action(_) => self.0 = true,
+            MdPiece::Container(container) => {
+                for piece in container.pieces {
+                    self.process_piece(piece)?;
+                }
+            }
+            MdPiece::FencedBlock(_) | MdPiece::Raw(_) => (),
+        }
+        Ok(())
+    }
+}
+
+/// Whether `content` has any action. Documents that don't parse do, so
+/// that processing them says why.
+pub fn has_actions(content: &str) -> bool {
+    let mut actions = Actions::default();
+    actions.process(content, &FileArg::StdHandle).is_err() || actions.0
+}
+
+/// `inputs` with directories and globs replaced by the documents they
+/// match, except for `ignored` ones, in order, and every file only once.
+pub fn expand(inputs: Vec<FileArg>, ignored: impl Fn(&Path) -> bool) -> Result<Vec<FileArg>> {
+    let mut seen = HashSet::new();
+    let mut expanded = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let files = match &input {
+            FileArg::File(path) if path.is_dir() => find(path, None, &ignored)?,
+            FileArg::File(path) if !path.exists() && is_glob(path.as_os_str()) => {
+                let (dir, pattern) = split(path);
+                find(&dir, Some(&pattern), &ignored)?
+            }
+            FileArg::File(path) => {
+                if seen.insert(path.clone()) {
+                    expanded.push(input);
+                }
+                continue;
+            }
+            FileArg::StdHandle => {
+                expanded.push(input);
+                continue;
+            }
+        };
+        if files.is_empty() {
+            eprintln!("warning: found no documents with actions in {input:?}");
+        }
+        for file in files {
+            if seen.insert(file.clone()) {
+                expanded.push(FileArg::File(file));
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// Documents with actions in `dir`, or the files with actions that match
+/// `pattern` relative to it, sorted by name.
+fn find(
+    dir: &Path,
+    pattern: Option<&str>,
+    ignored: &impl Fn(&Path) -> bool,
+) -> Result<Vec<PathBuf>> {
+    let glob = pattern
+        .map(|x| GlobBuilder::new(x).literal_separator(true).build())
+        .transpose()
+        .with_context(|| format!("invalid glob {pattern:?}"))?
+        .map(|x| x.compile_matcher());
+    let root = match dir.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => dir,
+    };
+    let mut files = Vec::new();
+    let walk = WalkBuilder::new(root)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .sort_by_file_name(Ord::cmp)
+        .build();
+    for entry in walk {
+        let entry = entry.with_context(|| format!("failed to search {root:?}"))?;
+        if !entry.file_type().is_some_and(|x| x.is_file()) {
+            continue;
+        }
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let matches = match &glob {
+            Some(glob) => glob.is_match(relative),
+            None => path
+                .extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|x| EXTENSIONS.contains(&x)),
+        };
+        if !matches || ignored(path) {
+            continue;
+        }
+        let content =
+            std::fs::read(path).with_context(|| format!("failed to read file {path:?}"))?;
+        // like images that a glob matches too
+        let Ok(content) = String::from_utf8(content) else {
+            continue;
+        };
+        if has_actions(&content) {
+            files.push(dir.join(relative));
+        }
+    }
+    Ok(files)
+}
+
+fn is_glob(s: &OsStr) -> bool {
+    s.to_string_lossy().contains(['*', '?', '[', '{'])
+}
+
+/// The directories of `pattern` before its first wildcard, and the rest.
+fn split(pattern: &Path) -> (PathBuf, String) {
+    let mut dir = PathBuf::new();
+    let mut components = pattern.components();
+    while let Some(x) = components.clone().next() {
+        if is_glob(x.as_os_str()) {
+            break;
+        }
+        dir.push(x);
+        components.next();
+    }
+    (dir, components.as_path().to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::TempDir;
+
+    #[test]
+    fn test_expand() {
+        let dir = TempDir::new("inputs");
+        for x in [".git", "docs/sub", "target", "vendor"] {
+            std::fs::create_dir_all(dir.join(x)).unwrap();
+        }
+        let write = |path: &str, content: &str| std::fs::write(dir.join(path), content).unwrap();
+        write(".gitignore", "target/\n");
+        write(".mdshignore", "vendor/\n");
+        for x in [
+            "README.md",
+            "docs/b.markdown",
+            "docs/sub/c.md",
+            "target/d.md",
+            "vendor/e.md",
+        ] {
+            write(x, "`> $ true`\n");
+        }
+        write("docs/a.md", "# no actions\n");
+        write("docs/notes.txt", "`> $ true`\n");
+        std::fs::write(dir.join("docs/image.png"), b"\x89PNG\r\n\x1a\n\xff").unwrap();
+        let expand_in = |inputs: &[&str]| {
+            let inputs = inputs.iter().map(|x| FileArg::File(dir.join(x))).collect();
+            expand(inputs, |_| false)
+                .unwrap()
+                .into_iter()
+                .map(|x| match x {
+                    FileArg::File(path) => path.strip_prefix(&dir).unwrap().display().to_string(),
+                    FileArg::StdHandle => "-".to_owned(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            expand_in(&[""]),
+            ["README.md", "docs/b.markdown", "docs/sub/c.md"]
+        );
+        assert_eq!(
+            expand_in(&["docs/*"]),
+            ["docs/b.markdown", "docs/notes.txt"]
+        );
+        // explicit files are kept, and nothing comes twice
+        assert_eq!(
+            expand_in(&["docs/a.md", "**/*.md", "README.md"]),
+            ["docs/a.md", "README.md", "docs/sub/c.md"]
+        );
+    }
+}
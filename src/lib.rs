@@ -1,29 +1,83 @@
+pub mod ansi;
+pub mod cache;
 pub mod cli;
+pub mod config;
+pub mod diff;
 pub mod executor;
+pub mod filter;
+pub mod include;
+pub mod inputs;
 mod nom_ext;
+pub mod normalize;
 pub mod parser;
+pub mod plan;
+pub mod report;
+pub mod root;
+pub mod sandbox;
+pub mod secret;
+pub mod session;
 #[cfg(test)]
 mod tests;
+pub mod trust;
 
 use std::io::Write;
 
 use anyhow::{Context, Result};
-use nom::Finish;
+use nom::{Finish, Offset as _};
 
 use crate::parser::fmt_nom_error;
 
 const BEGIN_MDSH: &str = "<!-- BEGIN mdsh -->";
 const END_MDSH: &str = "<!-- END mdsh -->";
 
+/// 1-based line and column of byte `offset` in `input`.
+pub(crate) fn position(input: &str, offset: usize) -> (usize, usize) {
+    let before = &input[..offset];
+    let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// `text` as lines numbered from `line`, like in compiler errors,
+/// with a caret under `column` of the first line if there is one.
+pub(crate) fn snippet(line: usize, text: &str, column: Option<usize>) -> String {
+    const LINES: usize = 3;
+    let width = (line + LINES).to_string().len();
+    let mut snippet = format!("{:width$} |\n", "");
+    for (i, x) in text.lines().take(LINES).enumerate() {
+        snippet += &format!("{:width$} | {x}\n", line + i);
+    }
+    if text.lines().nth(LINES).is_some() {
+        snippet += &format!("{:width$} | ...\n", "");
+    }
+    match column {
+        Some(column) => snippet += &format!("{:width$} | {:>column$}", "", "^"),
+        None => snippet += &format!("{:width$} |", ""),
+    }
+    snippet
+}
+
 pub trait Processor<'a> {
     fn process_piece(&mut self, piece: MdPiece<'a>) -> Result<()>;
 
+    /// Called with the whole input before its pieces get processed.
+    fn start(&mut self, _input: &'a str, _input_pipe: &cli::FileArg) {}
+
+    /// Called after the last piece got processed.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     fn process(&'a mut self, input: &'a str, input_pipe: &cli::FileArg) -> Result<()> {
+        self.start(input, input_pipe);
         // TODO: consider streaming directly from BufReader or smth,
         // see https://github.com/rust-bakery/nom/issues/1145
         let mut iter = nom::combinator::iterator(input, parser::markdown_piece());
 
-        for piece in iter.by_ref() {
+        for mut piece in iter.by_ref() {
+            piece.locate(input);
             self.process_piece(piece)
                 .context("processing markdown piece")?;
         }
@@ -34,7 +88,7 @@ pub trait Processor<'a> {
             .map_err(fmt_nom_error(input, &format!("{input_pipe:?}")))
             .context("parsing markdown")?;
 
-        Ok(())
+        self.finish()
     }
 }
 
@@ -51,12 +105,17 @@ impl<W> Cleaner<W> {
 impl<'a, W: Write> Processor<'a> for Cleaner<W> {
     fn process_piece(&mut self, piece: MdPiece<'a>) -> Result<()> {
         match piece {
-            MdPiece::FencedBlock => (),
+            MdPiece::FencedBlock(_) => (),
             MdPiece::Action((source, _action)) => {
                 self.out.write_all(source.as_bytes())?;
             }
-            MdPiece::RawLine(raw_line) => {
-                self.out.write_all(raw_line.as_bytes())?;
+            MdPiece::Raw(raw) => {
+                self.out.write_all(raw.as_bytes())?;
+            }
+            MdPiece::Container(container) => {
+                for piece in container.pieces {
+                    self.process_piece(piece)?;
+                }
             }
         }
         Ok(())
@@ -65,18 +124,64 @@ impl<'a, W: Write> Processor<'a> for Cleaner<W> {
 
 #[derive(Debug)]
 pub enum MdPiece<'a> {
-    FencedBlock,
+    /// Previously generated output, dropped on every pass unless
+    /// its action failed.
+    FencedBlock(&'a str),
     Action(parser::ActionWithSource<'a>),
-    RawLine(&'a str),
+    /// Markdown that is copied through as is.
+    Raw(&'a str),
+    Container(Container<'a>),
+}
+
+impl MdPiece<'_> {
+    /// Sets the spans of the actions in it, which is a slice of `input`.
+    fn locate(&mut self, input: &str) {
+        match self {
+            MdPiece::Action((source, action)) => {
+                let start = input.offset(source);
+                action.span = start..start + source.len();
+            }
+            MdPiece::Container(container) => {
+                for piece in &mut container.pieces {
+                    piece.locate(input);
+                }
+            }
+            MdPiece::FencedBlock(_) | MdPiece::Raw(_) => (),
+        }
+    }
+}
+
+/// Blockquote or list item. Its pieces still cover the container's
+/// lines exactly, prefixes included.
+#[derive(Debug)]
+pub struct Container<'a> {
+    /// Prefix for the lines of generated blocks: `> ` for blockquotes,
+    /// the content indentation for list items.
+    pub prefix: String,
+    pub pieces: Vec<MdPiece<'a>>,
 }
 
 #[cfg(test)]
 pub(crate) mod test {
-    use crate::{cli::FileArg, executor::TheProcessor, Cleaner, Processor};
+    use std::path::{Path, PathBuf};
+
+    use crate::{
+        cache::Cache,
+        cli::{FileArg, PlanFormat},
+        executor::{Environment, Settings, Shell, TheProcessor},
+        plan::Planner,
+        sandbox::Sandbox,
+        Cleaner, Processor,
+    };
 
     pub(crate) fn process(input: &str) -> anyhow::Result<String> {
+        process_with(Settings::default(), input)
+    }
+
+    pub(crate) fn process_with(settings: Settings, input: &str) -> anyhow::Result<String> {
         let mut buf = Vec::new();
         TheProcessor::new(std::ffi::OsStr::new("."), &mut buf)
+            .with_settings(settings)
             .process(input, &FileArg::StdHandle)?;
         Ok(String::from_utf8(buf)?)
     }
@@ -87,6 +192,36 @@ pub(crate) mod test {
         Ok(String::from_utf8(buf)?)
     }
 
+    /// Directory of a test, removed when the test ends, even if it fails.
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("mdsh-test-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
     macro_rules! assert_process_eq {
         ($i:tt, $o:tt) => {
             const ANSI_R: &str = "\x1b[1;31m";
@@ -121,6 +256,437 @@ pub(crate) mod test {
     }
     pub(crate) use assert_process_eq;
 
+    #[test]
+    fn test_posix_shell() {
+        let settings = Settings {
+            shell: Shell::new("sh".to_owned(), None),
+            ..Default::default()
+        };
+        assert_eq!(
+            process_with(settings.clone(), "`> $ echo hi | cat`\n").unwrap(),
+            "`> $ echo hi | cat`\n\n<!-- BEGIN mdsh -->\nhi\n<!-- END mdsh -->\n"
+        );
+        // the prologue applies to scripts as well
+        let input = "```sh > $\nfalse\necho unreachable\n```\n";
+        assert!(process_with(settings.clone(), input).is_err());
+        assert!(process_with(settings, "```sh > $\necho $undefined\n```\n").is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let started = std::time::Instant::now();
+        let err = process("# Slow\n\n`> $ {timeout=1s} sleep 10 | cat`\n").unwrap_err();
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(
+            err.root_cause().to_string(),
+            "`sleep 10 | cat` timed out after 1s"
+        );
+        assert!(format!("{err:#}").contains("<stdin>:3:1\n"), "{err:#}");
+        let err = process("`> $ {timeout=999999999999999999h} true`\n").unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "expected a duration like `30s`, got \"999999999999999999h\""
+        );
+    }
+
+    #[test]
+    fn test_session() {
+        let input = "`> $ cd samples; greet() { echo \"hi from ${PWD##*/}\"; }`\n\n\
+                     ```> $ cat\nfoo\n```\n\n`> $ greet`\n";
+        let settings = Settings {
+            session: true,
+            ..Default::default()
+        };
+        assert!(process_with(settings, input)
+            .unwrap()
+            .ends_with("`> $ greet`\n\n<!-- BEGIN mdsh -->\nhi from samples\n<!-- END mdsh -->\n"));
+
+        // a failed block leaves nothing behind, and the session goes on
+        let input = "`> $ cd samples; x=1`\n\n`> $ x=2; echo oops >&2; false; echo no`\n\n\
+                     `> $ echo ${PWD##*/} $x`\n";
+        let mut failures = Vec::new();
+        let mut buf = Vec::new();
+        TheProcessor::new(std::ffi::OsStr::new("."), &mut buf)
+            .with_settings(Settings {
+                session: true,
+                ..Default::default()
+            })
+            .with_failures(Some(&mut failures))
+            .process(input, &FileArg::StdHandle)
+            .unwrap();
+        assert!(String::from_utf8(buf).unwrap().ends_with(
+            "`> $ echo ${PWD##*/} $x`\n\n<!-- BEGIN mdsh -->\nsamples 1\n<!-- END mdsh -->\n"
+        ));
+        assert_eq!(
+            failures
+                .iter()
+                .map(|x| (x.line, x.status.as_deref(), x.stderr.clone()))
+                .collect::<Vec<_>>(),
+            [(Some(3), Some("exit status: 1"), vec!["oops".to_owned()])]
+        );
+    }
+
+    #[test]
+    fn test_hermetic() {
+        let settings = Settings {
+            env: Some(Environment::hermetic(&["PATH".to_owned()])),
+            ..Default::default()
+        };
+        assert_eq!(
+            process_with(settings.clone(), "`> $ echo ${HOME-unset} $LANG $TZ`\n").unwrap(),
+            "`> $ echo ${HOME-unset} $LANG $TZ`\n\n<!-- BEGIN mdsh -->\nunset C.UTF-8 UTC\n<!-- END mdsh -->\n"
+        );
+        // `!` variables only expand what commands would see
+        assert!(process_with(settings.clone(), "```!\nTZ=$TZ\n```\n").is_ok());
+        assert!(process_with(settings, "```!\nDIR=$HOME\n```\n").is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        let input = "# Plan\n\n`> $ touch ran`\n\n> ```sh > yaml < {cache=false}\n> ran\n> ```\n";
+        let mut buf = Vec::new();
+        let settings = Settings {
+            config: Some("mdsh.toml".into()),
+            ..Default::default()
+        };
+        Planner::new(std::ffi::OsStr::new("/docs"), PlanFormat::Text, &mut buf)
+            .with_settings(settings)
+            .process(input, &FileArg::from_str_unsafe("README.md"))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "README.md:3: inline code, Execute -> Markdown, data_line: \"touch ran\", \
+             data: 0 bytes, work dir: /docs, runs with: `bash` after the prologue \
+             `set -euo pipefail` (set in mdsh.toml)\n\
+             README.md:5: code block in blockquote, Read -> CodeBlock(\"yaml\") {cache=false}, \
+             data: 4 bytes, work dir: /docs\n"
+        );
+        assert!(!std::path::Path::new("ran").exists());
+    }
+
+    #[test]
+    fn test_trust() {
+        use crate::trust::{commands, TrustStore};
+
+        let dir = TempDir::new("trust");
+        let store = TrustStore::new(dir.join("store"));
+        let document = dir.join("README.md");
+        std::fs::write(&document, "").unwrap();
+
+        let settings = Settings::default();
+        let before = "`! A=1`\n\n`> $ echo one`\n\n`> < LICENSE`\n\n```sh > $\necho two\n```\n";
+        let before = commands(&document, before, &settings).unwrap();
+        assert_eq!(before.len(), 3);
+        assert_eq!(store.untrusted(&document, &before).unwrap().len(), 3);
+        store.allow(&document, &before).unwrap();
+        assert!(store.untrusted(&document, &before).unwrap().is_empty());
+
+        let after = "`! BASH_ENV=./evil.sh`\n\n`> $ echo one`\n\n```sh > $\necho three\n```\n";
+        let after = commands(&document, after, &settings).unwrap();
+        let untrusted = store.untrusted(&document, &after).unwrap();
+        assert_eq!(
+            untrusted
+                .iter()
+                .map(|x| (x.line, x.summary.as_str()))
+                .collect::<Vec<_>>(),
+            [(1, "! BASH_ENV=./evil.sh"), (5, "echo three")]
+        );
+
+        // the same commands, run with something else
+        let settings = Settings {
+            shell: Shell::new("bash".to_owned(), Some("echo PWNED".to_owned())),
+            config: Some("mdsh.toml".into()),
+            ..Default::default()
+        };
+        let before = "`! A=1`\n\n`> $ echo one`\n\n```py > $\nprint(1)\n```\n";
+        let error = crate::trust::check(&store, &document, before, &settings).unwrap_err();
+        let name = document.display();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{name} has commands that weren't allowed to run:\n  \
+                 {name}:3: echo one\n  {name}:5: print(1), with `python3`\n\
+                 Shell commands run with `bash` after the prologue `echo PWNED`, as mdsh.toml says.\n\
+                 Review them, for example with `mdsh --plan -i {name}`, then run `mdsh allow {name}`."
+            )
+        );
+    }
+
+    #[test]
+    fn test_binary_output() {
+        // output that isn't UTF-8 stays as is, unless filters need text
+        for (input, output) in [
+            ("`> $ printf 'a\\377b'`\n", &b"\na\xffb\n"[..]),
+            (
+                "`! secret TOKEN=a`\n\n`> $ printf 'a\\377b'`\n",
+                &b"\n***\xffb\n"[..],
+            ),
+        ] {
+            let mut buf = Vec::new();
+            TheProcessor::new(std::ffi::OsStr::new("."), &mut buf)
+                .process(input, &FileArg::StdHandle)
+                .unwrap();
+            let output = [output, b"<!-- END mdsh -->\n"].concat();
+            assert!(buf.ends_with(&output), "{}", String::from_utf8_lossy(&buf));
+        }
+    }
+
+    #[test]
+    fn test_secrets() {
+        let output =
+            process("`! secret=1 secretive=2`\n\n`> $ echo $secret $secretive`\n").unwrap();
+        assert!(output.contains("\n1 2\n"), "{output}");
+
+        let dir = TempDir::new("secrets");
+        let settings = Settings {
+            cache: Some(Cache::new(dir.to_path_buf(), false)),
+            ..Default::default()
+        };
+        let input = "`! secret TOKEN=s3cr3t-value`\n\n`> $ {cache=true} echo token is $TOKEN`\n";
+        let output = process_with(settings, input).unwrap();
+        assert!(output.contains("\ntoken is ***\n"), "{output}");
+        let entries = std::fs::read_dir(&*dir).unwrap().collect::<Vec<_>>();
+        let [Ok(entry)] = &entries[..] else {
+            panic!("expected one cached output, got {entries:?}");
+        };
+        let cached = std::fs::read_to_string(entry.path()).unwrap();
+        assert_eq!(cached, "token is ***\n");
+
+        // failures go to the summary and to reports
+        let input = "`! secret TOKEN=s3cr3t-value`\n\n`> $ echo \"bad $TOKEN\" >&2; false`\n";
+        let (mut failures, mut outcomes) = (Vec::new(), Vec::new());
+        TheProcessor::new(std::ffi::OsStr::new("."), &mut Vec::new())
+            .with_failures(Some(&mut failures))
+            .with_outcomes(Some(&mut outcomes))
+            .process(input, &FileArg::StdHandle)
+            .unwrap();
+        assert_eq!(failures[0].stderr, ["bad ***"]);
+        let error = outcomes[1].error.as_deref().unwrap();
+        assert!(error.ends_with("\n    bad ***"), "{error}");
+    }
+
+    #[test]
+    fn test_error_location() {
+        assert_eq!(crate::position("ab\ncdé f", 8), (2, 5));
+        let err = process("# title\n\n> `> $ echo failed >&2; exit 4`\n").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "processing markdown piece: <stdin>:3:1\n  |\n3 | > `> $ echo failed >&2; exit 4`\n  |: \
+             getting data: command failed with exit status: 4, its stderr ended with:\n    failed"
+        );
+        let err = process("```!\nA=1\nB x\n```\n").unwrap_err();
+        assert!(
+            format!("{err:#}").ends_with("<stdin>:3:2: expected `=`\n  |\n3 | B x\n  |  ^"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let input = "`> $ echo same`\n\n<!-- BEGIN mdsh -->\nsame\n<!-- END mdsh -->\n\n\
+                     `> $ echo new`\n\n`> $ exit 3`\n\n`! A=1`\n";
+        let mut outcomes = Vec::new();
+        let mut buf = Vec::new();
+        TheProcessor::new(std::ffi::OsStr::new("."), &mut buf)
+            .with_failures(Some(&mut Vec::new()))
+            .with_outcomes(Some(&mut outcomes))
+            .process(input, &FileArg::StdHandle)
+            .unwrap();
+        assert_eq!(
+            outcomes
+                .iter()
+                .map(|x| (
+                    x.line,
+                    x.command.as_str(),
+                    x.status,
+                    x.output_bytes,
+                    x.changed
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (1, "echo same", Some(0), 5, false),
+                (7, "echo new", Some(0), 4, true),
+                (9, "exit 3", Some(3), 0, true),
+                (11, "A=1", None, 4, false),
+            ]
+        );
+        assert_eq!(
+            outcomes[2].error.as_deref(),
+            Some("getting data: command failed with exit status: 3")
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        use crate::{
+            config::{Config, Options},
+            trust::{commands, TrustStore},
+        };
+
+        let dir = TempDir::new("config");
+        std::fs::create_dir_all(dir.join("docs/slow")).unwrap();
+        for x in ["docs/a.md", "docs/slow/b.md"] {
+            std::fs::write(dir.join(x), "").unwrap();
+        }
+        let path = dir.join("mdsh.toml");
+        std::fs::write(
+            &path,
+            "inputs = [\"docs/\"]\nshell = \"sh\"\ntimeout = \"5s\"\nignore = [\"docs/slow/**\"]\n\n\
+             [[overrides]]\npaths = [\"docs/slow/*.md\"]\ntimeout = \"1m\"\nsession = true\n",
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        assert!(matches!(&config.inputs[..], [FileArg::File(x)] if *x == dir.join("docs/")));
+        assert!(config.ignores(&dir.join("docs/slow/b.md")));
+        assert!(!config.ignores(&dir.join("docs/a.md")));
+        assert!(config
+            .options_for(&FileArg::File(dir.join("docs/a.md")))
+            .is_none());
+        let options = config
+            .options_for(&FileArg::File(dir.join("docs/slow/b.md")))
+            .unwrap();
+        // the command line beats the overrides, which beat the rest
+        let opt = <crate::cli::Opt as clap::Parser>::parse_from(["mdsh", "--no-session"]);
+        let cli = Options::from_cli(&opt).unwrap();
+        let mut settings = Settings::default();
+        options.merge(cli).apply(&mut settings);
+        assert_eq!(settings.timeout, Some(std::time::Duration::from_secs(60)));
+        assert!(!settings.session);
+        assert_eq!(
+            format!("{:?}", settings.shell),
+            format!("{:?}", Shell::new("sh".into(), None))
+        );
+
+        // commands allowed with other options don't run with these
+        let store = TrustStore::new(dir.join("store"));
+        let document = dir.join("docs/slow/b.md");
+        let allowed = commands(&document, "`> $ true`\n", &Settings::default()).unwrap();
+        store.allow(&document, &allowed).unwrap();
+        let commands = commands(&document, "`> $ true`\n", &settings).unwrap();
+        assert_eq!(store.untrusted(&document, &commands).unwrap().len(), 1);
+
+        std::fs::write(&path, "timeout = 5\n").unwrap();
+        let err = format!("{:#}", Config::load(&path).unwrap_err());
+        assert!(
+            err.contains("invalid configuration") && err.contains("timeout"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let input = "`> $ exit 3`\n\n<!-- BEGIN mdsh -->\nold\n<!-- END mdsh -->\n\n\
+                     - `> $ echo oops >&2; false`\n\n`> $ echo ok`\n";
+        let mut failures = Vec::new();
+        let mut buf = Vec::new();
+        TheProcessor::new(std::ffi::OsStr::new("."), &mut buf)
+            .with_failures(Some(&mut failures))
+            .process(input, &FileArg::StdHandle)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "`> $ exit 3`\n\n<!-- BEGIN mdsh -->\nold\n<!-- END mdsh -->\n\n\
+             - `> $ echo oops >&2; false`\n\n  <!-- BEGIN mdsh -->\n  \
+             <!-- mdsh: the action failed -->\n  <!-- END mdsh -->\n\n\
+             `> $ echo ok`\n\n<!-- BEGIN mdsh -->\nok\n<!-- END mdsh -->\n"
+        );
+        assert_eq!(
+            failures
+                .iter()
+                .map(|x| (x.line, x.status.as_deref(), x.stderr.clone()))
+                .collect::<Vec<_>>(),
+            [
+                (Some(1), Some("exit status: 3"), vec![]),
+                (Some(7), Some("exit status: 1"), vec!["oops".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_root() {
+        use crate::root::Root;
+
+        let samples = Settings {
+            root: Root::Dir("samples".into()),
+            ..Default::default()
+        };
+        assert!(process_with(samples.clone(), "`> < samples/example.json`\n").is_ok());
+        let err = process_with(samples.clone(), "# title\n\n`> < Cargo.toml`\n").unwrap_err();
+        let err = format!("{err:#}");
+        assert!(
+            err.contains("<stdin>:3:1\n") && err.contains("include \"Cargo.toml\" is outside"),
+            "{err}"
+        );
+        let err = format!("{:#}", process_with(samples, "`> $ true`\n").unwrap_err());
+        assert!(
+            err.contains("<stdin>:1:1\n") && err.contains("work dir \".\" is outside"),
+            "{err}"
+        );
+        // the repository is the root
+        assert!(process("`> < Cargo.toml`\n").is_ok());
+        let anywhere = Settings {
+            root: Root::Anywhere,
+            ..Default::default()
+        };
+        assert!(process_with(anywhere, "`> < /etc/passwd`\n").is_ok());
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = TempDir::new("cache");
+        let cached = Settings {
+            cache: Some(Cache::new(dir.to_path_buf(), false)),
+            ..Default::default()
+        };
+        let refreshed = Settings {
+            cache: Some(Cache::new(dir.to_path_buf(), true)),
+            ..Default::default()
+        };
+        let input = "`> $ {inputs=Cargo.toml} echo $RANDOM$RANDOM`\n";
+        let first = process_with(cached.clone(), input).unwrap();
+        assert_eq!(process_with(cached.clone(), input).unwrap(), first);
+        assert_ne!(process_with(refreshed, input).unwrap(), first);
+        let input = "`> $ {cache=true} echo $RANDOM$RANDOM`\n";
+        assert_eq!(
+            process_with(cached.clone(), input).unwrap(),
+            process_with(cached.clone(), input).unwrap()
+        );
+        // nothing says which files these read
+        for input in [
+            "`> $ echo $RANDOM$RANDOM`\n",
+            "`> $ {inputs=Cargo.toml cache=false} echo $RANDOM$RANDOM`\n",
+        ] {
+            assert_ne!(
+                process_with(cached.clone(), input).unwrap(),
+                process_with(cached.clone(), input).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sandbox() {
+        let settings = Settings {
+            sandbox: Some(Sandbox::new(&[]).unwrap()),
+            ..Default::default()
+        };
+        if let Err(e) = process_with(settings.clone(), "`> $ true`\n") {
+            eprintln!("skipping, no user namespaces: {e:#}");
+            return;
+        }
+        // the checkout may be in `/tmp`, and so in the sandbox's too
+        let host = TempDir::new("sandbox");
+        let input = format!(
+            "`> $ id -u; touch /tmp/x; ls /tmp/x; test -e {} || echo hidden`\n",
+            host.display()
+        );
+        assert_eq!(
+            process_with(settings.clone(), &input).unwrap(),
+            format!("{input}\n<!-- BEGIN mdsh -->\n65534\n/tmp/x\nhidden\n<!-- END mdsh -->\n")
+        );
+        assert!(process_with(settings, "`> $ touch README.md`\n").is_err());
+    }
+
     #[test]
     fn test_whole_file() {
         let file_in = String::from_utf8(std::fs::read("spec.clear.md").unwrap()).unwrap();
@@ -1,22 +1,152 @@
 use std::{
     fs::File,
-    io::{self, prelude::*},
+    io::{self, prelude::*, IsTerminal},
+    path::{Path, PathBuf},
 };
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use mdsh::{
-    cli::{FileArg, Opt, Parent},
-    executor::TheProcessor,
+    cache::Cache,
+    cli::{FileArg, Opt, Parent, PlanFormat, ReportFormat, SubCommand},
+    config::{Config, Options},
+    diff,
+    executor::{Settings, TheProcessor},
+    inputs,
+    plan::Planner,
+    report::{self, Failure, Outcome},
+    root::{self, Root},
+    sandbox::Sandbox,
+    secret::Secrets,
+    trust::{self, TrustStore},
     Cleaner, Processor,
 };
 
+/// What to do with the inputs.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Run,
+    /// Fails on changes instead of making them.
+    Frozen,
+    /// Prints changes instead of making them.
+    Diff,
+    Clean,
+    Plan(PlanFormat),
+}
+
+/// What every input is processed with, and what is gathered from them.
+struct State {
+    settings: Settings,
+    mode: Mode,
+    trust: Option<TrustStore>,
+    failures: Option<Vec<Failure>>,
+    outcomes: Option<Vec<Outcome>>,
+}
+
 fn main() -> anyhow::Result<()> {
     let opt = Opt::parse();
-    let clean = opt.clean;
-    let frozen = opt.frozen;
-    let inputs = opt.inputs;
+    let cli = Options::from_cli(&opt)?;
+    let trust_dir = opt.trust_dir.map_or_else(TrustStore::default_dir, Ok);
+    let config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => {
+            let root = opt
+                .root
+                .clone()
+                .unwrap_or_else(|| root::detect(Path::new(".")));
+            Config::discover(&root)?.unwrap_or_default()
+        }
+    };
+    let ignored = |path: &Path| config.ignores(path);
+    let default_inputs = || match &config.inputs[..] {
+        [] => vec![FileArg::from_str_unsafe("./README.md")],
+        inputs => inputs.to_vec(),
+    };
+    let mut settings = Settings {
+        cache: (!opt.no_cache).then(|| Cache::new(opt.cache_dir, opt.refresh)),
+        sandbox: opt
+            .sandbox
+            .then(|| Sandbox::new(&opt.sandbox_write))
+            .transpose()?,
+        root: match (opt.root, opt.allow_outside_root) {
+            (_, true) => Root::Anywhere,
+            (Some(dir), false) => Root::Dir(
+                dir.canonicalize()
+                    .with_context(|| format!("failed to find the root {dir:?}"))?,
+            ),
+            (None, false) => Root::Detect,
+        },
+        deny_leaks: opt.frozen,
+        ..Default::default()
+    };
+    config
+        .options
+        .clone()
+        .merge(cli.clone())
+        .apply(&mut settings);
+    // the command line still beats the overrides
+    let settings_for = |input: &FileArg| {
+        let options = config
+            .options_for(input)
+            .unwrap_or_else(|| config.options.clone());
+        let mut settings = settings.clone();
+        settings.config = config.path.clone().filter(|_| options.sets_runner(&cli));
+        options.merge(cli.clone()).apply(&mut settings);
+        settings
+    };
+
+    if let Some(SubCommand::Allow { files }) = opt.command {
+        let store = TrustStore::new(trust_dir?);
+        let files = match files {
+            files if !files.is_empty() => files.into_iter().map(FileArg::File).collect(),
+            _ => default_inputs(),
+        };
+        let files = inputs::expand(files, ignored)?;
+        for input in files {
+            let FileArg::File(file) = &input else {
+                continue;
+            };
+            let count = trust::allow(&store, file, &settings_for(&input))?;
+            eprintln!("allowed {count} commands of {}", file.display());
+        }
+        return Ok(());
+    }
+
+    let mode = match (opt.clean, opt.plan) {
+        (true, _) => Mode::Clean,
+        (false, Some(format)) => Mode::Plan(format),
+        (false, None) if opt.frozen => Mode::Frozen,
+        (false, None) if opt.diff => Mode::Diff,
+        (false, None) => Mode::Run,
+    };
+    // skipping the check is always the choice of whoever runs mdsh
+    let trust = match mode {
+        Mode::Run | Mode::Frozen | Mode::Diff if !opt.trust_all => {
+            Some(TrustStore::new(trust_dir?))
+        }
+        _ => None,
+    };
+    let report = opt
+        .report
+        .map(|x| {
+            let format = ReportFormat::from_str(&x[0], true)
+                .map_err(|e| anyhow::anyhow!("invalid report format {:?}: {e}", x[0]))?;
+            anyhow::Ok((format, PathBuf::from(&x[1])))
+        })
+        .transpose()?;
+    let inputs = match opt.inputs {
+        inputs if !inputs.is_empty() => inputs,
+        _ => default_inputs(),
+    };
+    let inputs = inputs::expand(inputs, ignored)?;
+    if let Some(env) = &settings.env {
+        eprintln!(
+            "hermetic environment, forwarding: {}",
+            env.forwarded.join(" ")
+        );
+    }
 
+    let mut jobs = Vec::with_capacity(inputs.len());
     if let [_, _, ..] = &inputs[..] {
         opt.output
             .is_none()
@@ -32,7 +162,7 @@ fn main() -> anyhow::Result<()> {
                 .parent()
                 .context("an input file has no parent directory")?;
             let output = input.clone();
-            process_file(&input, &output, &work_dir, clean, frozen)?;
+            jobs.push((input, output, work_dir));
         }
     } else if let [input, ..] = &inputs[..] {
         let output = opt.output.unwrap_or_else(|| input.clone());
@@ -45,7 +175,47 @@ fn main() -> anyhow::Result<()> {
             },
             |buf| Ok(Parent::from_parent_path_buf(buf)),
         )?;
-        process_file(input, &output, &work_dir, clean, frozen)?;
+        jobs.push((input.clone(), output, work_dir));
+    }
+
+    let mut state = State {
+        settings: settings.clone(),
+        mode,
+        trust,
+        failures: opt.keep_going.then(Vec::new),
+        outcomes: report.is_some().then(Vec::new),
+    };
+    let mut result = Ok(());
+    for (input, output, work_dir) in jobs {
+        state.settings = settings_for(&input);
+        let res = process_file(&input, &output, &work_dir, &mut state);
+        match (res, &mut state.failures) {
+            (Err(e), Some(failures)) => {
+                eprintln!("error: {e:#}");
+                let file = match &input {
+                    FileArg::StdHandle => "<stdin>".to_owned(),
+                    FileArg::File(path) => path.display().to_string(),
+                };
+                failures.push(Failure::new(&file, None, "", &e, &Secrets::default()));
+            }
+            (res @ Err(_), None) => {
+                result = res;
+                break;
+            }
+            (Ok(()), _) => {}
+        }
+    }
+    // the report matters most when something went wrong
+    if let (Some((format, path)), Some(outcomes)) = (report, &state.outcomes) {
+        let mut file = File::create(&path)
+            .with_context(|| format!("failed to open file {path:?} for writing"))?;
+        report::write(format, outcomes, &mut file)
+            .with_context(|| format!("failed to write the report {path:?}"))?;
+    }
+    result?;
+    if let Some(failures @ [_, ..]) = state.failures.as_deref() {
+        eprint!("\n{}", report::table(failures));
+        anyhow::bail!("{} actions or files failed", failures.len());
     }
 
     Ok(())
@@ -55,45 +225,101 @@ fn process_file(
     input: &FileArg,
     output: &FileArg,
     work_dir: &Parent,
-    clean: bool,
-    frozen: bool,
+    state: &mut State,
 ) -> anyhow::Result<()> {
     let input_content = read_file(input)?;
+    let State {
+        settings,
+        mode,
+        trust,
+        failures,
+        outcomes,
+    } = state;
+    let mode = *mode;
+    let failures = failures.as_mut();
+    let start = outcomes.as_ref().map_or(0, Vec::len);
 
     let work_dir = work_dir.as_path_buf().as_os_str();
+    let clean = match mode {
+        Mode::Run | Mode::Frozen | Mode::Diff => false,
+        Mode::Clean => true,
+        Mode::Plan(format) => {
+            return Planner::new(work_dir, format, io::stdout())
+                .with_settings(settings.clone())
+                .process(&input_content, input);
+        }
+    };
+    // piping a document into mdsh is explicit enough
+    if let (Some(trust), FileArg::File(path)) = (trust.as_ref(), input) {
+        trust::check(trust, path, &input_content, settings)?;
+    }
+    // the same processor, whichever output it writes to
+    let process = |out: &mut dyn Write| match clean {
+        true => Cleaner::new(out).process(&input_content, input),
+        false => TheProcessor::new(work_dir, out)
+            .with_settings(settings.clone())
+            .with_failures(failures)
+            .with_outcomes(outcomes.as_mut())
+            .process(&input_content, input),
+    };
+    if let Mode::Frozen | Mode::Diff = mode {
+        let mut buffer = Vec::with_capacity(8192);
+        process(&mut buffer)?;
+        let output = String::from_utf8_lossy(&buffer);
+        let content = input_content.trim_end();
+        if output.trim_end() == content {
+            return Ok(());
+        }
+        if let (Mode::Frozen, Some(outcomes)) = (mode, outcomes) {
+            let outcomes = outcomes[start..].iter_mut();
+            for outcome in outcomes.filter(|x| x.changed && x.error.is_none()) {
+                outcome.error = Some("the generated block is out of date".to_owned());
+            }
+        }
+        // the file keeps its ending, whatever it is
+        let output = format!("{}{}", output.trim_end(), &input_content[content.len()..]);
+        let name = match input {
+            FileArg::StdHandle => "<stdin>".to_owned(),
+            FileArg::File(path) => path.display().to_string(),
+        };
+        // `git apply` takes paths from the top of the repository
+        let path = match input {
+            FileArg::File(path) => path.canonicalize().ok().and_then(|path| {
+                let root = settings.root.resolve(path.parent()?);
+                root.into_iter()
+                    .chain(std::env::current_dir().ok())
+                    .find_map(|root| Some(path.strip_prefix(root).ok()?.display().to_string()))
+            }),
+            FileArg::StdHandle => None,
+        };
+        let patch = diff::unified(path.as_ref().unwrap_or(&name), &input_content, &output)?;
+        let colored = |terminal: bool| {
+            if terminal && std::env::var_os("NO_COLOR").is_none() {
+                diff::colorize(&patch)
+            } else {
+                patch.clone()
+            }
+        };
+        if let Mode::Diff = mode {
+            print!("{}", colored(io::stdout().is_terminal()));
+            return Ok(());
+        }
+        eprint!("{}", colored(io::stderr().is_terminal()));
+        anyhow::bail!("{name} is not up to date, run mdsh to update it");
+    }
     match (input, output) {
         (FileArg::File(inf), FileArg::File(outf)) if inf == outf => {
             let mut buffer = Vec::with_capacity(8192);
-            if clean {
-                Cleaner::new(&mut buffer).process(&input_content, input)?;
-            } else {
-                TheProcessor::new(work_dir, &mut buffer).process(&input_content, input)?;
-            }
-            let file_unmodified_check = !frozen || input_content.as_bytes() == buffer;
-
+            process(&mut buffer)?;
             std::fs::write(outf, buffer.trim_ascii_end())
                 .with_context(|| format!("failed to write file {outf:?}"))?;
-
-            file_unmodified_check
-                .then_some(())
-                .context("File modified")?;
         }
         (_, FileArg::File(outf)) => {
             let mut outf_handle = File::create(outf)
                 .with_context(|| format!("failed to open file {outf:?} for writing"))?;
-            if clean {
-                Cleaner::new(&mut outf_handle).process(&input_content, input)?;
-            } else {
-                TheProcessor::new(work_dir, &mut outf_handle).process(&input_content, input)?;
-            }
-        }
-        (_, FileArg::StdHandle) => {
-            if clean {
-                Cleaner::new(&mut io::stdout()).process(&input_content, input)?;
-            } else {
-                TheProcessor::new(work_dir, &mut io::stdout()).process(&input_content, input)?;
-            }
+            process(&mut outf_handle)?;
         }
+        (_, FileArg::StdHandle) => process(&mut io::stdout())?,
     }
     Ok(())
 }
@@ -140,9 +366,4 @@ fn wrap_nl(s: String) -> String {
         format!("\n{}\n", s)
     }
 }
-
-// remove all ANSI escape characters
-fn filter_ansi(s: String) -> String {
-    RE_ANSI_FILTER.replace_all(&s, "").to_string()
-}
 */
@@ -0,0 +1,112 @@
+//! Rules that rewrite volatile parts of command output, like timestamps
+//! and temporary paths, into stable placeholders, so that `--frozen`
+//! doesn't fail just because a command ran at another time or place.
+use anyhow::Result;
+use nom::{combinator::all_consuming, Finish as _, Parser as _};
+use regex::{Captures, Regex};
+
+use crate::parser::{fmt_nom_error, normalize_rules};
+
+/// Rules of a document are in this `!` variable, actions can override
+/// them with `{normalize=...}`.
+pub const VARIABLE: &str = "MDSH_NORMALIZE";
+
+/// Name, pattern and placeholder of the built-in rules.
+const BUILTIN: &[(&str, &str, &str)] = &[
+    (
+        "dates",
+        r"\b\d{4}-\d{2}-\d{2}(?:[T ]\d{2}:\d{2}(?::\d{2}(?:[.,]\d+)?)?(?:Z|[+-]\d{2}:?\d{2})?)?",
+        "<date>",
+    ),
+    (
+        "durations",
+        r"\b(?:\d+(?:\.\d+)?(?:ns|µs|us|ms|s|m|h))+\b|\b\d+(?:\.\d+)?\s?(?:seconds?|secs?|minutes?|mins?|hours?)\b",
+        "<duration>",
+    ),
+    (
+        "tmp",
+        r#"(?:/private)?/(?:tmp|var/folders)/[^\s'"`:]*"#,
+        "<tmp>",
+    ),
+    ("hashes", r"\b[0-9a-f]{7,}\b", "<hash>"),
+];
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    regex: Regex,
+    /// Replaces each match, `$1` is the first capture group.
+    placeholder: String,
+    /// Matches that are left alone, like numbers and words for `hashes`.
+    skip: Option<fn(&str) -> bool>,
+}
+
+impl Rule {
+    pub fn new(regex: Regex, placeholder: String) -> Self {
+        Self {
+            regex,
+            placeholder,
+            skip: None,
+        }
+    }
+
+    /// `dates`, `durations`, `tmp` or `hashes`.
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (_, pattern, placeholder) = BUILTIN.iter().find(|(x, _, _)| *x == name)?;
+        let mut rule = Self::new(Regex::new(pattern).ok()?, (*placeholder).to_owned());
+        if name == "hashes" {
+            rule.skip = Some(|x| {
+                x.bytes().all(|x| x.is_ascii_digit()) || x.bytes().all(|x| x.is_ascii_alphabetic())
+            });
+        }
+        Some(rule)
+    }
+}
+
+/// Parses rules like `dates, tmp, /pid \d+/pid <pid>/`, or `none`.
+pub fn parse(spec: &str) -> Result<Vec<Rule>> {
+    if spec.trim() == "none" {
+        return Ok(Vec::new());
+    }
+    let (_, rules) = all_consuming(normalize_rules())
+        .parse(spec)
+        .finish()
+        .map_err(fmt_nom_error(spec, "normalization rules"))?;
+    Ok(rules)
+}
+
+/// Applies `rules` in order.
+pub fn apply(rules: &[Rule], data: &[u8]) -> Vec<u8> {
+    let mut text = String::from_utf8_lossy(data).into_owned();
+    for rule in rules {
+        text = rule
+            .regex
+            .replace_all(&text, |caps: &Captures| {
+                if rule.skip.is_some_and(|skip| skip(&caps[0])) {
+                    return caps[0].to_owned();
+                }
+                let mut placeholder = String::new();
+                caps.expand(&rule.placeholder, &mut placeholder);
+                placeholder
+            })
+            .into_owned();
+    }
+    text.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let rules = parse("dates, durations tmp,hashes").unwrap();
+        let output = "2024-03-01 12:00:00+01:00 took 250ms in /private/tmp/x.1 \
+                      for 0123abc, not 1234567 or defaced\n";
+        assert_eq!(
+            String::from_utf8(apply(&rules, output.as_bytes())).unwrap(),
+            "<date> took <duration> in <tmp> for <hash>, not 1234567 or defaced\n"
+        );
+        assert!(parse("none").unwrap().is_empty());
+        assert!(parse("dates,/(/x/").is_err());
+    }
+}
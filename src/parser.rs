@@ -1,21 +1,41 @@
+//! Position-preserving markdown parser.
+//!
+//! The input is split into CommonMark blocks (fenced and indented code
+//! blocks, HTML blocks, headings, thematic breaks and paragraphs) so that
+//! mdsh commands are only picked up where a markdown renderer would see
+//! them. Every [`MdPiece`] borrows its exact bytes from the input, so
+//! pieces that aren't acted upon round-trip unchanged.
+use std::{borrow::Cow, collections::BTreeMap as Map};
+
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, tag, take_until, take_until1, take_while, take_while_m_n},
+    bytes::complete::{
+        escaped, tag, tag_no_case, take_till, take_till1, take_until, take_until1, take_while,
+        take_while1, take_while_m_n,
+    },
     character::complete::{
-        alphanumeric1, anychar, char, multispace0, multispace1, newline, none_of, one_of, space0,
+        alphanumeric1, anychar, char, digit1, multispace0, multispace1, newline, none_of, one_of,
+        satisfy, space0, space1,
+    },
+    combinator::{
+        all_consuming, consumed, cut, eof, fail, not, opt, peek, recognize, rest, success, verify,
     },
-    combinator::{consumed, cut, eof, fail, not, opt, peek, recognize, rest, success},
     error::context,
-    multi::{many0_count, many1_count},
-    sequence::{delimited, preceded, terminated},
-    Parser as _,
+    error::ParseError as _,
+    multi::{many0_count, many1, many1_count, separated_list1},
+    sequence::{delimited, preceded, separated_pair, terminated},
+    Offset as _, Parser as _,
 };
 use nom_language::error::VerboseError;
 
 use crate::{
-    executor::{Action, Command, InType, OutType},
+    executor::{Action, Command, ContainerKind, InType, OutType},
+    filter::Filter,
+    include::Selector,
     nom_ext::FnParser,
-    MdPiece, BEGIN_MDSH, END_MDSH,
+    normalize::Rule,
+    secret::Pattern,
+    Container, MdPiece, BEGIN_MDSH, END_MDSH,
 };
 
 /// Trait alias, sort of like
@@ -38,26 +58,26 @@ impl<'a, T, X: nom::Parser<&'a str, Output = T, Error = VerboseError<&'a str>>>
 
 pub fn markdown_piece<'a>() -> impl Parser<'a, MdPiece<'a>> {
     alt((
-        FencedBlockParser.map(|_| MdPiece::FencedBlock),
-        action_with_source().map(MdPiece::Action),
+        recognize(alt((numbered_fenced_block, FencedBlockParser))).map(MdPiece::FencedBlock),
         preceded(tag(BEGIN_MDSH), fail()),
-        comment().map(MdPiece::RawLine),
-        non_actionable_code_block().map(MdPiece::RawLine),
-        recognize(context("raw line", (take_until("\n"), newline))).map(MdPiece::RawLine),
+        blank_line().map(MdPiece::Raw),
+        consumed(actionable_code_block()).map(MdPiece::Action),
+        non_actionable_code_block().map(MdPiece::Raw),
+        indented_code_block().map(MdPiece::Raw),
+        consumed(actionable_comment()).map(MdPiece::Action),
+        html_block().map(MdPiece::Raw),
+        alt((atx_heading(), thematic_break())).map(MdPiece::Raw),
+        container.map(MdPiece::Container),
+        consumed(paragraph_action()).map(MdPiece::Action),
+        paragraph().map(MdPiece::Raw),
     ))
 }
 
 pub type ActionWithSource<'a> = (&'a str, Action<'a>);
 
-fn action_with_source<'a>() -> impl Parser<'a, ActionWithSource<'a>> {
-    consumed(alt((
-        actionable_code_block(),
-        inline_code(),
-        link(),
-        actionable_comment(),
-    )))
-}
-
+/// Previously generated block, along with the blank lines that were
+/// written before it.
+///
 /// Need this to avoid "recursive opaque type" error.
 /// Recursive definition to allow included markdown code
 /// to be also processable by mdsh
@@ -74,16 +94,13 @@ impl<'a> nom::Parser<&'a str> for FencedBlockParser {
         context(
             "fenced block",
             delimited(
-                (tag(BEGIN_MDSH), newline),
+                (many0_count(blank_line()), tag(BEGIN_MDSH), newline),
                 recognize(
                     // markdown_piece(), // TODO
                     many0_count(not(tag(BEGIN_MDSH).or(tag(END_MDSH))).and(anychar))
                         .and(alt((peek(tag(END_MDSH)), recognize(Self)))),
                 ),
-                cut(tag(END_MDSH)
-                    .and(space0)
-                    .and(recognize(newline).or(eof))
-                    .and(multispace0)),
+                cut(tag(END_MDSH).and(space0).and(line_end())),
             ),
         )
         .map(|_| ())
@@ -91,6 +108,53 @@ impl<'a> nom::Parser<&'a str> for FencedBlockParser {
     }
 }
 
+/// Previously generated block with numbered markers, used when
+/// the output contains markers itself. It ends at the first `END`
+/// marker with the same number.
+fn numbered_fenced_block(input: &str) -> IResult<'_, ()> {
+    let (rest, (_, _, id, _, _)) = (
+        many0_count(blank_line()),
+        tag("<!-- BEGIN mdsh "),
+        digit1,
+        tag(" -->"),
+        line_end(),
+    )
+        .parse(input)?;
+    let end = format!("<!-- END mdsh {id} -->");
+    let (rest, _) = context(
+        "numbered fenced block",
+        cut((
+            take_until(end.as_str()),
+            tag(end.as_str()),
+            space0,
+            line_end(),
+        )),
+    )
+    .parse(rest)?;
+    Ok((rest, ()))
+}
+
+/// Either a newline or the end of input.
+fn line_end<'a>() -> impl Parser<'a, &'a str> {
+    alt((tag("\n"), eof))
+}
+
+/// A single non-empty line, including its newline if there is one.
+fn line<'a>() -> impl Parser<'a, &'a str> {
+    recognize((not(eof), take_till(|c| c == '\n'), line_end()))
+}
+
+/// A line containing only spaces and tabs.
+fn blank_line<'a>() -> impl Parser<'a, &'a str> {
+    recognize((not(eof), space0, line_end()))
+}
+
+/// Up to 3 spaces of indentation, which CommonMark allows before most
+/// block starts without turning them into an indented code block.
+fn indent<'a>() -> impl Parser<'a, &'a str> {
+    take_while_m_n(0, 3, |c| c == ' ')
+}
+
 /// Link container:
 /// ```md
 /// [> yaml < yaml example](./sample.yaml)`
@@ -102,15 +166,20 @@ fn link<'a>() -> impl Parser<'a, Action<'a>> {
             char('['),
             command(),
             not(char('[')),
-            cut((take_until("]"), tag("]("))),
-            cut(filepath()),
-            cut((char(')'), newline)),
+            take_until("]"),
+            tag("]("),
+            take_till1(|c: char| c == ')' || c.is_whitespace()),
+            char(')'),
+            line_end(),
         ),
     )
-    .map(|(_, command, _, _, filepath, _)| Action {
+    .map(|(_, command, _, _, _, filepath, _, _)| Action {
         command,
-        data_line: Some(filepath),
+        data_line: Some(filepath.into()),
         data: None,
+        lang: None,
+        kind: ContainerKind::Link,
+        span: 0..0,
     })
 }
 
@@ -118,7 +187,7 @@ fn out_type<'a>() -> impl Parser<'a, OutType<'a>> {
     context(
         "output type",
         alt((
-            (char('>'), space0, filepath()).map(|x| OutType::CodeBlock(x.2)),
+            (char('>'), space0, filepath()).map(|x| OutType::CodeBlock(x.2.into())),
             (char('>')).map(|_| OutType::Markdown),
             (char('!')).map(|_| OutType::Environment),
         )),
@@ -149,15 +218,44 @@ fn in_type<'a>() -> impl Parser<'a, InType> {
 fn command<'a>() -> impl Parser<'a, Command<'a>> {
     context(
         "mdsh command",
-        (out_type(), space0, in_type()).map(|(out_type, _, in_type)| Command { in_type, out_type }),
+        (
+            out_type(),
+            space0,
+            in_type(),
+            opt(preceded(space0, attributes())),
+        )
+            .map(|(out_type, _, in_type, attributes)| Command {
+                in_type,
+                out_type,
+                attributes: attributes.unwrap_or_default(),
+            }),
     )
 }
 
-fn comment<'a>() -> impl Parser<'a, &'a str> {
-    recognize(context(
-        "comment",
-        (tag("<!--"), cut(take_until("-->")), tag("-->")),
-    ))
+/// Options of a single action, right after its `in_cmd`:
+/// ```md
+/// `> $ {timeout=5s} curl https://example.com`
+/// ```
+fn attributes<'a>() -> impl Parser<'a, Map<String, String>> {
+    let key = recognize(many1_count(alt((alphanumeric1, recognize(one_of("_-"))))));
+    let value = take_till1(|c: char| c.is_whitespace() || c == '}');
+    context(
+        "attributes",
+        terminated(
+            delimited(
+                char('{'),
+                separated_list1(space1, separated_pair(key, char('='), value)),
+                char('}'),
+            ),
+            peek(alt((space1, line_end()))),
+        ),
+    )
+    .map(|pairs| {
+        pairs
+            .into_iter()
+            .map(|(k, v): (&str, &str)| (k.to_owned(), v.to_owned()))
+            .collect()
+    })
 }
 
 fn actionable_comment<'a>() -> impl Parser<'a, Action<'a>> {
@@ -166,7 +264,7 @@ fn actionable_comment<'a>() -> impl Parser<'a, Action<'a>> {
         delimited(
             tag("<!--"),
             take_until1("-->"),
-            tag("-->").and(space0).and(newline),
+            tag("-->").and(space0).and(line_end()),
         )
         .and_then(
             (
@@ -182,8 +280,11 @@ fn actionable_comment<'a>() -> impl Parser<'a, Action<'a>> {
             )
                 .map(|(_, command, _, data_line, _, data)| Action {
                     command,
-                    data_line,
-                    data: Some(data),
+                    data_line: data_line.map(Into::into),
+                    data: Some(data.into()),
+                    lang: None,
+                    kind: ContainerKind::Comment,
+                    span: 0..0,
                 }),
         ),
     )
@@ -197,70 +298,688 @@ fn inline_code<'a>() -> impl Parser<'a, Action<'a>> {
     context(
         "inline code",
         recognize(take_while_m_n(1, 2, |x| x == '`').and(not(char('`'))))
-            .flat_map(|q1| terminated(take_until1(q1), tag(q1).and(newline)))
+            .flat_map(|q1| terminated(take_until1(q1), tag(q1).and(line_end())))
             .and_then((command(), space0, rest))
             .map(|(command, _, rest)| Action {
                 command,
-                data_line: Some(rest),
+                data_line: Some(rest.into()),
                 data: None,
+                lang: None,
+                kind: ContainerKind::InlineCode,
+                span: 0..0,
             }),
     )
 }
 
+/// Inline code and links are only actionable when they make up
+/// a whole line, and nothing continues them into a longer paragraph.
+fn paragraph_action<'a>() -> impl Parser<'a, Action<'a>> {
+    terminated(
+        action_line(),
+        (
+            not(setext_underline()),
+            alt((eof, peek(paragraph_interrupt()))),
+        ),
+    )
+}
+
+/// A line that is an inline code or link action on its own. Such lines
+/// were actions before mdsh knew about paragraphs and HTML blocks, so they
+/// still end those, and documents keep their generated blocks.
+fn action_line<'a>() -> impl Parser<'a, Action<'a>> {
+    line().and_then(all_consuming(alt((inline_code(), link()))))
+}
+
+/// A paragraph: a run of lines until a blank line or another block
+/// that is allowed to interrupt it.
+fn paragraph<'a>() -> impl Parser<'a, &'a str> {
+    recognize((
+        line(),
+        many0_count(preceded(not(paragraph_interrupt()), line())),
+    ))
+}
+
+/// Block starts that end a paragraph without needing a blank line.
+fn paragraph_interrupt<'a>() -> impl Parser<'a, &'a str> {
+    alt((
+        blank_line(),
+        recognize((indent(), fence())),
+        atx_heading(),
+        thematic_break(),
+        recognize((indent(), char('>'))),
+        recognize(peek(html_block_start())),
+        recognize(peek(action_line())),
+        recognize((
+            indent(),
+            alt((
+                recognize(one_of("-+*")),
+                recognize((char('1'), one_of(".)"))),
+            )),
+            space1,
+            not(line_end()),
+        )),
+    ))
+}
+
+/// A line of `=` or `-` turning the preceding paragraph into a heading.
+fn setext_underline<'a>() -> impl Parser<'a, &'a str> {
+    recognize((
+        indent(),
+        alt((take_while1(|c| c == '='), take_while1(|c| c == '-'))),
+        space0,
+        line_end(),
+    ))
+}
+
+fn atx_heading<'a>() -> impl Parser<'a, &'a str> {
+    recognize((
+        indent(),
+        take_while_m_n(1, 6, |c| c == '#'),
+        alt((line_end(), recognize((one_of(" \t"), line())))),
+    ))
+}
+
+fn thematic_break<'a>() -> impl Parser<'a, &'a str> {
+    recognize(verify(preceded(indent(), line()), |l: &str| {
+        let l = l.trim_end();
+        l.chars()
+            .next()
+            .filter(|c| "-*_".contains(*c))
+            .is_some_and(|c| {
+                l.chars().all(|x| x == c || x == ' ' || x == '\t')
+                    && l.chars().filter(|x| *x == c).count() >= 3
+            })
+    }))
+}
+
+/// Lines indented by 4 or more columns, possibly separated by blank lines.
+fn indented_code_block<'a>() -> impl Parser<'a, &'a str> {
+    fn indented_line<'a>() -> impl Parser<'a, &'a str> {
+        recognize((
+            alt((tag("    "), recognize((indent(), char('\t'))))),
+            not(blank_line()),
+            line(),
+        ))
+    }
+    context(
+        "indented code block",
+        recognize(many1_count(alt((
+            indented_line(),
+            recognize((many1_count(blank_line()), peek(indented_line()))),
+        )))),
+    )
+}
+
+/// Blockquote or list item. Its lines are parsed again with the
+/// container prefixes stripped, and the resulting pieces are mapped
+/// back onto the input, so they still cover it byte for byte.
+fn container(input: &str) -> IResult<'_, Container<'_>> {
+    let (rest, (prefix, lines)) = alt((blockquote_lines, list_item_lines)).parse(input)?;
+
+    let mut content = String::with_capacity(input.offset(rest));
+    let mut map = LineMap {
+        input,
+        lines: Vec::with_capacity(lines.len()),
+    };
+    for (line, strip) in lines {
+        map.lines.push((input.offset(line), strip, content.len()));
+        content.push_str(&line[strip..]);
+    }
+
+    let content = content.as_str();
+    let mut iter = nom::combinator::iterator(content, markdown_piece());
+    let pieces = iter
+        .by_ref()
+        .map(|piece| map.lift(content, piece))
+        .collect();
+    iter.finish().map_err(|e| {
+        e.map(|e| VerboseError {
+            errors: e
+                .errors
+                .into_iter()
+                .map(|(i, kind)| (&input[map.offset(content.offset(i))..], kind))
+                .collect(),
+        })
+    })?;
+
+    Ok((rest, Container { prefix, pieces }))
+}
+
+type IResult<'a, T> = nom::IResult<&'a str, T, VerboseError<&'a str>>;
+
+/// Lines of a container, with the length of the prefix to strip from each.
+type ContainerLines<'a> = (String, Vec<(&'a str, usize)>);
+
+fn blockquote_lines(input: &str) -> IResult<'_, ContainerLines<'_>> {
+    fn marker<'a>() -> impl Parser<'a, &'a str> {
+        recognize((indent(), char('>'), opt(char(' '))))
+    }
+    let (_, (indent, _)) = context("blockquote", peek((indent(), char('>')))).parse(input)?;
+    let prefix = format!("{indent}> ");
+
+    let mut lines = vec![];
+    let mut rest = input;
+    loop {
+        let strip = match marker().parse(rest) {
+            Ok((_, marker)) => marker.len(),
+            Err(_) if is_lazy_continuation(&lines, rest) => 0,
+            Err(_) => break,
+        };
+        let (next, line) = line().parse(rest)?;
+        if line.len() == strip {
+            break;
+        }
+        lines.push((line, strip));
+        rest = next;
+    }
+    Ok((rest, (prefix, lines)))
+}
+
+fn list_item_lines(input: &str) -> IResult<'_, ContainerLines<'_>> {
+    let (_, (marker, spaces, empty)) = context(
+        "list item",
+        peek((
+            recognize((indent(), list_marker())),
+            space0,
+            opt(line_end()).map(|x| x.is_some()),
+        )),
+    )
+    .parse(input)?;
+    if spaces.is_empty() && !empty {
+        return Err(nom::Err::Error(VerboseError::from_error_kind(
+            input,
+            nom::error::ErrorKind::Space,
+        )));
+    }
+    // content of the first line is indented by 1 to 4 spaces after the
+    // marker, otherwise it starts with an indented code block
+    let width = if empty || spaces.len() > 4 {
+        marker.len() + 1
+    } else {
+        marker.len() + spaces.len()
+    };
+    let indentation = |line: &str| line.len() - line.trim_start_matches(' ').len();
+
+    let (mut rest, first) = verify(line(), |l: &str| l.len() > marker.len()).parse(input)?;
+    let mut lines = vec![(first, width.min(marker.len() + spaces.len()))];
+    loop {
+        if let Ok((next, blanks)) = recognize(many1_count(blank_line())).parse(rest) {
+            // blank lines only belong to the item if it goes on after them
+            if next.is_empty() || indentation(next) < width {
+                break;
+            }
+            for blank in blanks.split_inclusive('\n') {
+                lines.push((blank, indentation(blank).min(width)));
+            }
+            rest = next;
+            continue;
+        }
+        let strip = if indentation(rest) >= width {
+            width
+        } else if is_lazy_continuation(&lines, rest)
+            && not((indent(), list_marker(), alt((space1, line_end()))))
+                .parse(rest)
+                .is_ok()
+        {
+            0
+        } else {
+            break;
+        };
+        let (next, line) = line().parse(rest)?;
+        if line.len() == strip {
+            break;
+        }
+        lines.push((line, strip));
+        rest = next;
+    }
+    Ok((rest, (" ".repeat(width), lines)))
+}
+
+fn list_marker<'a>() -> impl Parser<'a, &'a str> {
+    alt((
+        recognize(one_of("-+*")),
+        recognize((
+            take_while_m_n(1, 9, |c: char| c.is_ascii_digit()),
+            one_of(".)"),
+        )),
+    ))
+}
+
+/// A line that isn't part of a container by its prefix, but continues
+/// the paragraph that the container's last line is in.
+fn is_lazy_continuation(lines: &[(&str, usize)], rest: &str) -> bool {
+    lines
+        .last()
+        .is_some_and(|(line, strip)| !line[*strip..].trim().is_empty())
+        && not(alt((recognize(eof), paragraph_interrupt())))
+            .parse(rest)
+            .is_ok()
+}
+
+/// Where the lines of a container start in the input, how much prefix
+/// was stripped from them, and where they start in the stripped content.
+struct LineMap<'a> {
+    input: &'a str,
+    lines: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> LineMap<'a> {
+    /// Maps an offset in the content to an offset in the input.
+    /// Line starts map to the start of the prefix.
+    fn offset(&self, pos: usize) -> usize {
+        let i = self
+            .lines
+            .partition_point(|(_, _, start)| *start <= pos)
+            .saturating_sub(1);
+        let (line, strip, start) = self.lines[i];
+        if pos == start {
+            line
+        } else {
+            line + strip + (pos - start)
+        }
+    }
+
+    fn slice(&self, content: &str, piece: &str) -> &'a str {
+        let start = content.offset(piece);
+        &self.input[self.offset(start)..self.offset(start + piece.len())]
+    }
+
+    fn lift(&self, content: &str, piece: MdPiece<'_>) -> MdPiece<'a> {
+        match piece {
+            MdPiece::FencedBlock(block) => MdPiece::FencedBlock(self.slice(content, block)),
+            MdPiece::Action((source, action)) => {
+                MdPiece::Action((self.slice(content, source), action.into_owned()))
+            }
+            MdPiece::Raw(raw) => MdPiece::Raw(self.slice(content, raw)),
+            MdPiece::Container(container) => MdPiece::Container(Container {
+                prefix: container.prefix,
+                pieces: container
+                    .pieces
+                    .into_iter()
+                    .map(|piece| self.lift(content, piece))
+                    .collect(),
+            }),
+        }
+    }
+}
+
+/// Block-level HTML, which CommonMark passes through as is.
+/// Starts and end conditions follow the 7 kinds of the specification.
+fn html_block<'a>() -> impl Parser<'a, &'a str> {
+    const RAW_TEXT_ENDS: &[&str] = &["</script>", "</pre>", "</style>", "</textarea>"];
+    context(
+        "html block",
+        alt((
+            preceded(
+                peek(html_raw_text_start()),
+                lines_until_containing(RAW_TEXT_ENDS),
+            ),
+            preceded(
+                peek((indent(), tag("<!--"))),
+                lines_until_containing(&["-->"]),
+            ),
+            preceded(peek((indent(), tag("<?"))), lines_until_containing(&["?>"])),
+            preceded(
+                peek((indent(), tag("<![CDATA["))),
+                lines_until_containing(&["]]>"]),
+            ),
+            preceded(
+                peek((indent(), tag("<!"), satisfy(|c| c.is_ascii_alphabetic()))),
+                lines_until_containing(&[">"]),
+            ),
+            preceded(peek(html_block_level_start()), lines_until_blank()),
+            preceded(peek(html_tag_line()), lines_until_blank()),
+        )),
+    )
+}
+
+/// HTML block starts that may interrupt a paragraph (all but the last kind).
+fn html_block_start<'a>() -> impl Parser<'a, ()> {
+    alt((
+        html_raw_text_start(),
+        (indent(), alt((tag("<!--"), tag("<?"), tag("<![CDATA[")))).map(|_| ()),
+        (indent(), tag("<!"), satisfy(|c| c.is_ascii_alphabetic())).map(|_| ()),
+        html_block_level_start(),
+    ))
+}
+
+fn html_raw_text_start<'a>() -> impl Parser<'a, ()> {
+    (
+        indent(),
+        char('<'),
+        alt((
+            tag_no_case("script"),
+            tag_no_case("pre"),
+            tag_no_case("style"),
+            tag_no_case("textarea"),
+        )),
+        alt((recognize(one_of(" \t>")), line_end())),
+    )
+        .map(|_| ())
+}
+
+fn html_block_level_start<'a>() -> impl Parser<'a, ()> {
+    const BLOCK_TAGS: &[&str] = &[
+        "address",
+        "article",
+        "aside",
+        "base",
+        "basefont",
+        "blockquote",
+        "body",
+        "caption",
+        "center",
+        "col",
+        "colgroup",
+        "dd",
+        "details",
+        "dialog",
+        "dir",
+        "div",
+        "dl",
+        "dt",
+        "fieldset",
+        "figcaption",
+        "figure",
+        "footer",
+        "form",
+        "frame",
+        "frameset",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "head",
+        "header",
+        "hr",
+        "html",
+        "iframe",
+        "legend",
+        "li",
+        "link",
+        "main",
+        "menu",
+        "menuitem",
+        "nav",
+        "noframes",
+        "ol",
+        "optgroup",
+        "option",
+        "p",
+        "param",
+        "search",
+        "section",
+        "summary",
+        "table",
+        "tbody",
+        "td",
+        "tfoot",
+        "th",
+        "thead",
+        "title",
+        "tr",
+        "track",
+        "ul",
+    ];
+    (
+        indent(),
+        char('<'),
+        opt(char('/')),
+        verify(alphanumeric1, |name: &str| {
+            BLOCK_TAGS.contains(&name.to_ascii_lowercase().as_str())
+        }),
+        alt((recognize(one_of(" \t>")), tag("/>"), line_end())),
+    )
+        .map(|_| ())
+}
+
+/// A complete opening or closing tag alone on its line.
+fn html_tag_line<'a>() -> impl Parser<'a, ()> {
+    fn tag_name<'a>() -> impl Parser<'a, &'a str> {
+        recognize((
+            satisfy(|c| c.is_ascii_alphabetic()),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '-'),
+        ))
+    }
+    (
+        indent(),
+        char('<'),
+        alt((
+            recognize((
+                tag_name(),
+                opt((space1, take_till(|c| c == '>' || c == '\n'))),
+            )),
+            recognize((char('/'), tag_name(), space0)),
+        )),
+        char('>'),
+        space0,
+        line_end(),
+    )
+        .map(|_| ())
+}
+
+/// Lines up to and including the first one containing any of `ends`
+/// (ASCII case-insensitive), or up to the end of input.
+fn lines_until_containing<'a>(ends: &'static [&'static str]) -> impl Parser<'a, &'a str> {
+    let contains_end = |l: &str| {
+        let l = l.to_ascii_lowercase();
+        ends.iter().any(|end| l.contains(end))
+    };
+    recognize((
+        many0_count(verify(line(), move |l: &str| !contains_end(l))),
+        opt(line()),
+    ))
+}
+
+/// Lines up to a blank one, or one with an action after the first.
+fn lines_until_blank<'a>() -> impl Parser<'a, &'a str> {
+    recognize((
+        line(),
+        many0_count(preceded(
+            not(alt((blank_line(), recognize(action_line())))),
+            line(),
+        )),
+    ))
+}
+
+fn fence<'a>() -> impl Parser<'a, &'a str> {
+    alt((
+        recognize((tag("```"), take_while(|x| x == '`'))),
+        recognize((tag("~~~"), take_while(|x| x == '~'))),
+    ))
+}
+
 fn non_actionable_code_block<'a>() -> impl Parser<'a, &'a str> {
     fn meta_line<'a>() -> impl Parser<'a, ()> {
-        take_until("\n").and(newline).map(|_| ())
+        line().map(|_| ())
     }
-    recognize(code_block(FnParser::new(meta_line)))
+    recognize(code_block(FnParser::new(meta_line), false))
 }
 
 fn actionable_code_block<'a>() -> impl Parser<'a, Action<'a>> {
-    fn meta_line<'a>() -> impl Parser<'a, (Command<'a>, Option<&'a str>)> {
+    type MetaLine<'a> = (Option<&'a str>, Command<'a>, Option<&'a str>);
+    fn meta_line<'a>() -> impl Parser<'a, MetaLine<'a>> {
         (
-            opt(filepath()).and(space0),
+            opt(filepath()),
+            space0,
             command(),
             space0,
-            opt(take_until1("\n")),
-            newline,
+            opt(take_till1(|c| c == '\n')),
+            line_end(),
         )
-            .map(|(_srclang, command, _, data_line, _)| (command, data_line))
+            .map(|(lang, _, command, _, data_line, _)| (lang, command, data_line))
     }
     context(
         "code block with mdsh command",
-        code_block(FnParser::new(meta_line)),
+        code_block(FnParser::new(meta_line), true),
     )
-    .map(|((command, data_line), data)| Action {
+    .map(|((lang, command, data_line), data)| Action {
         command,
-        data_line,
+        data_line: data_line.map(Into::into),
         data: Some(data),
+        lang: lang.map(Into::into),
+        kind: ContainerKind::CodeBlock,
+        span: 0..0,
     })
 }
 
+/// Fenced code block. Without a closing fence, it runs until the end of
+/// input, unless `closed` is required.
 fn code_block<'a, X>(
     meta_line: FnParser<impl Parser<'a, X> + 'a>,
-) -> impl Parser<'a, (X, &'a str)> {
-    let fence = alt((
-        (tag("```"), take_while(|x| x == '`')),
-        (tag("~~~"), take_while(|x| x == '~')),
-    ));
+    closed: bool,
+) -> impl Parser<'a, (X, Cow<'a, str>)> {
     context(
         "code block",
-        recognize((space0, fence)).flat_map(move |q| {
+        (indent(), fence()).flat_map(move |(indent, q): (&'a str, &'a str)| {
+            let closing_fence = move || {
+                recognize((
+                    self::indent(),
+                    tag(q),
+                    take_while(move |x| q.starts_with(x)),
+                    space0,
+                    line_end(),
+                ))
+            };
             (
+                // backtick fences can't have backticks in their info string
+                peek(verify(line(), move |l: &str| {
+                    !q.starts_with('`') || !l.contains('`')
+                })),
                 meta_line.clone(),
-                cut(alt((
-                    peek(tag(q)).map(|_| "\n"), // covers the edge case with empty code block
-                    recognize(many0_count(not(newline.and(tag(q))).and(anychar)).and(newline)),
-                ))),
-                cut(tag(q).and(newline)),
+                recognize(many0_count(preceded(not(closing_fence()), line())))
+                    .map(move |data| strip_indent(data, indent.len())),
+                cut(alt((closing_fence(), verify(eof, move |_: &str| !closed)))),
             )
         }),
     )
-    .map(|(meta_line, data, _)| (meta_line, data))
+    .map(|(_, meta_line, data, _)| (meta_line, data))
+}
+
+/// Content lines of a fenced code block lose as much indentation
+/// as its opening fence has.
+fn strip_indent(data: &str, n: usize) -> Cow<'_, str> {
+    if n == 0 {
+        return data.into();
+    }
+    data.split_inclusive('\n')
+        .map(|line| &line[(line.len() - line.trim_start_matches(' ').len()).min(n)..])
+        .collect::<String>()
+        .into()
+}
+
+/// Selector of a partial file include, what comes after the `#` in
+/// `< path#selector`.
+pub fn include_selector<'a>() -> impl Parser<'a, Selector<'a>> {
+    fn line_number<'a>() -> impl Parser<'a, usize> {
+        digit1.map_res(str::parse)
+    }
+    context(
+        "include selector",
+        alt((
+            // only all of it, so that regions like `L2cache` stay regions
+            (
+                char('L'),
+                line_number(),
+                opt(preceded((char('-'), opt(char('L'))), line_number())),
+                eof,
+            )
+                .map(|(_, start, end, _)| Selector::Lines {
+                    start,
+                    end: end.unwrap_or(start),
+                }),
+            recognize(many1_count(alt((alphanumeric1, recognize(one_of("_-"))))))
+                .map(Selector::Region),
+            recognize((char('/'), rest)).map(Selector::Query),
+        )),
+    )
+}
+
+/// Up to the next unescaped `/`, which a `\/` doesn't end.
+fn delimited_by_slash<'a>() -> impl Parser<'a, String> {
+    terminated(
+        recognize(many0_count(alt((
+            recognize((char('\\'), anychar)),
+            recognize(none_of("/\\")),
+        )))),
+        char('/'),
+    )
+    .map(|x: &str| x.replace("\\/", "/"))
 }
 
-pub fn env_var_line<'a>() -> impl Parser<'a, Option<(&'a str, &'a str)>> {
+/// `/pattern/`
+fn regex<'a>() -> impl Parser<'a, regex::Regex> {
+    context(
+        "regex like /pattern/",
+        preceded(char('/'), delimited_by_slash()).map_res(|x| regex::Regex::new(&x)),
+    )
+}
+
+/// Filters at the end of a command or include, `|> head=20 |> sort`.
+pub fn filters<'a>() -> impl Parser<'a, Vec<Filter>> {
+    fn count<'a>() -> impl Parser<'a, usize> {
+        context("number of lines", digit1.map_res(str::parse))
+    }
+    let filter = alt((
+        preceded(tag("head="), cut(count())).map(Filter::Head),
+        preceded(tag("tail="), cut(count())).map(Filter::Tail),
+        preceded(tag("grep="), cut(regex())).map(Filter::Grep),
+        preceded(tag("replace="), cut((regex(), delimited_by_slash())))
+            .map(|(regex, replacement)| Filter::Replace(regex, replacement)),
+        tag("dedent").map(|_| Filter::Dedent),
+        tag("sort").map(|_| Filter::Sort),
+    ));
+    terminated(
+        many1(preceded((space0, tag("|>"), space0), filter)),
+        (space0, eof),
+    )
+}
+
+/// Normalization rules separated by commas or spaces: names of built-in
+/// ones like `dates`, and `/pattern/placeholder/`.
+pub fn normalize_rules<'a>() -> impl Parser<'a, Vec<Rule>> {
+    let rule = alt((
+        (regex(), cut(context("placeholder", delimited_by_slash())))
+            .map(|(regex, placeholder)| Rule::new(regex, placeholder)),
+        context("built-in rule", alphanumeric1.map_opt(Rule::builtin)),
+    ));
+    delimited(
+        multispace0,
+        separated_list1(take_while1(|c: char| c == ',' || c.is_whitespace()), rule),
+        multispace0,
+    )
+}
+
+/// Secrets to mask separated by commas or spaces: variable names like
+/// `TOKEN` or `AWS_*`, and `/pattern/`.
+pub fn secret_patterns<'a>() -> impl Parser<'a, Vec<Pattern>> {
+    let pattern = alt((
+        regex().map(Pattern::Regex),
+        context(
+            "variable name",
+            recognize((
+                many1_count(alphanumeric1.or(recognize(char('_')))),
+                opt(char('*')),
+            )),
+        )
+        .map(|x: &str| Pattern::Variable(x.to_owned())),
+    ));
+    delimited(
+        multispace0,
+        separated_list1(
+            take_while1(|c: char| c == ',' || c.is_whitespace()),
+            pattern,
+        ),
+        multispace0,
+    )
+}
+
+/// `NAME=value`, or `secret NAME=value` for a value to mask in output.
+pub fn env_var_line<'a>() -> impl Parser<'a, Option<(bool, &'a str, &'a str)>> {
     let kv_definition = (
+        opt(terminated(tag("secret"), space1)).map(|x| x.is_some()),
         recognize(many1_count(alphanumeric1.or(recognize(char('_'))))),
         cut(char('=')),
         cut(alt((
@@ -274,13 +993,11 @@ pub fn env_var_line<'a>() -> impl Parser<'a, Option<(&'a str, &'a str)>> {
                 escaped(none_of("'\\"), '\\', one_of("'\\")),
                 char('\''),
             ),
-            take_until(" "),
-            take_until("\n"),
-            rest,
+            take_till(|c| c == ' ' || c == '\n'),
         ))),
         opt(newline),
     )
-        .map(|(k, _, v, _)| (k, v));
+        .map(|(secret, k, _, v, _)| (secret, k, v));
     alt((
         (char('#'), take_until("\n"), newline).map(|_| None),
         (multispace1).map(|_| None),
@@ -300,3 +1017,20 @@ pub fn fmt_nom_error<'a>(
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_selector() {
+        let parse = |x| all_consuming(include_selector()).parse(x).unwrap().1;
+        let lines = |start, end| Selector::Lines { start, end };
+        assert_eq!(parse("L2"), lines(2, 2));
+        assert_eq!(parse("L2-L4"), lines(2, 4));
+        assert_eq!(parse("L2-4"), lines(2, 4));
+        assert_eq!(parse("L2cache"), Selector::Region("L2cache"));
+        assert_eq!(parse("L2-L4-old"), Selector::Region("L2-L4-old"));
+        assert_eq!(parse("/package"), Selector::Query("/package"));
+    }
+}
@@ -0,0 +1,154 @@
+//! Dry run that shows what the actions of a document would do,
+//! so that it can be reviewed before anything runs.
+use std::{ffi::OsStr, io::Write, path::Path};
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::{
+    cli::{FileArg, PlanFormat},
+    executor::{Action, OutType, Runner, Settings},
+    MdPiece, Processor,
+};
+
+pub struct Planner<'a, W> {
+    workdir: &'a OsStr,
+    format: PlanFormat,
+    settings: Settings,
+    input: &'a str,
+    file: String,
+    /// Blockquotes and list items around the current piece.
+    parents: Vec<&'static str>,
+    out: W,
+}
+
+impl<'a, W: Write> Planner<'a, W> {
+    pub fn new(workdir: &'a OsStr, format: PlanFormat, out: W) -> Self {
+        Self {
+            workdir,
+            format,
+            settings: Settings::default(),
+            input: "",
+            file: String::new(),
+            parents: Vec::new(),
+            out,
+        }
+    }
+
+    /// Shows what executed actions run with, as these settings say.
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    fn plan(&mut self, line: usize, action: &Action) -> Result<()> {
+        let command = &action.command;
+        let workdir = Path::new(self.workdir);
+        let workdir = std::path::absolute(workdir).unwrap_or_else(|_| workdir.to_owned());
+        let workdir = workdir.display();
+        let data = action.data.as_deref().map_or(0, str::len);
+        let runner = self.settings.runner(action);
+        let config = runner
+            .as_ref()
+            .and(self.settings.config.as_deref())
+            .map(|x| x.display().to_string());
+        match self.format {
+            PlanFormat::Text => {
+                let mut container = action.kind.to_string();
+                for parent in self.parents.iter().rev() {
+                    container += &format!(" in {parent}");
+                }
+                let attributes = command
+                    .attributes
+                    .iter()
+                    .map(|(k, v)| format!(" {k}={v}"))
+                    .collect::<String>();
+                write!(
+                    self.out,
+                    "{}:{line}: {container}, {:?} -> {:?}",
+                    self.file, command.in_type, command.out_type
+                )?;
+                if !attributes.is_empty() {
+                    write!(self.out, " {{{}}}", attributes.trim_start())?;
+                }
+                if let Some(data_line) = &action.data_line {
+                    write!(self.out, ", data_line: {data_line:?}")?;
+                }
+                write!(self.out, ", data: {data} bytes, work dir: {workdir}")?;
+                if let Some(runner) = &runner {
+                    write!(self.out, ", runs with: {runner}")?;
+                }
+                if let Some(config) = &config {
+                    write!(self.out, " (set in {config})")?;
+                }
+                writeln!(self.out)?;
+            }
+            PlanFormat::Json => {
+                let (out_type, out_lang) = match &command.out_type {
+                    OutType::Markdown => ("Markdown", None),
+                    OutType::Environment => ("Environment", None),
+                    OutType::CodeBlock(lang) => ("CodeBlock", Some(lang)),
+                };
+                let (shell, prologue, interpreter) = match runner {
+                    Some(Runner::Shell { command, prologue }) => {
+                        (Some(command), Some(prologue), None)
+                    }
+                    Some(Runner::Interpreter(command)) => (None, None, Some(command)),
+                    None => (None, None, None),
+                };
+                let plan = json!({
+                    "file": self.file,
+                    "line": line,
+                    "container": action.kind.to_string(),
+                    "parents": self.parents,
+                    "in_type": format!("{:?}", command.in_type),
+                    "out_type": out_type,
+                    "out_lang": out_lang,
+                    "attributes": command.attributes,
+                    "lang": action.lang,
+                    "data_line": action.data_line,
+                    "data_bytes": data,
+                    "work_dir": workdir.to_string(),
+                    "shell": shell,
+                    "prologue": prologue,
+                    "interpreter": interpreter,
+                    "config": config,
+                });
+                writeln!(self.out, "{plan}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Processor<'a> for Planner<'a, W> {
+    fn start(&mut self, input: &'a str, input_pipe: &FileArg) {
+        self.input = input;
+        self.file = match input_pipe {
+            FileArg::StdHandle => "<stdin>".to_owned(),
+            FileArg::File(path) => path.display().to_string(),
+        };
+    }
+
+    fn process_piece(&mut self, piece: MdPiece<'a>) -> Result<()> {
+        match piece {
+            MdPiece::FencedBlock(_) | MdPiece::Raw(_) => (),
+            MdPiece::Action((_, action)) => {
+                self.plan(crate::position(self.input, action.span.start).0, &action)?;
+            }
+            MdPiece::Container(container) => {
+                self.parents
+                    .push(if container.prefix.trim_start().starts_with('>') {
+                        "blockquote"
+                    } else {
+                        "list item"
+                    });
+                for piece in container.pieces {
+                    self.process_piece(piece)?;
+                }
+                self.parents.pop();
+            }
+        }
+        Ok(())
+    }
+}
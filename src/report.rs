@@ -0,0 +1,299 @@
+//! How the actions of a run went: what went wrong with `--keep-going`,
+//! summarized at the end, and every action with `--report`.
+use std::{fmt::Write as _, io, time::Duration};
+
+use serde_json::json;
+
+use crate::{cli::ReportFormat, executor::CommandFailed, secret::Secrets};
+
+/// How an action went.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub file: String,
+    pub line: usize,
+    /// First line of the command, include or data of the action.
+    pub command: String,
+    pub duration: Duration,
+    /// Exit code of the command, `None` if the action doesn't run one,
+    /// or it was killed.
+    pub status: Option<i32>,
+    /// Size of the output, before it's put in a block.
+    pub output_bytes: usize,
+    /// Whether the block that the action generated differs from the
+    /// previous one.
+    pub changed: bool,
+    pub error: Option<String>,
+}
+
+/// An action, or a whole file, that failed.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub file: String,
+    /// Of the action, `None` if the whole file failed.
+    pub line: Option<usize>,
+    /// First line of the command, include or data of the action.
+    pub command: String,
+    /// Exit status of the command, if it ran and failed.
+    pub status: Option<String>,
+    /// Last lines that the command wrote to stderr, or the error.
+    pub stderr: Vec<String>,
+}
+
+impl Failure {
+    /// With `secrets` masked in the command and what it wrote to stderr,
+    /// as both go to the summary and to reports.
+    pub fn new(
+        file: &str,
+        line: Option<usize>,
+        command: &str,
+        error: &anyhow::Error,
+        secrets: &Secrets,
+    ) -> Self {
+        let (status, stderr) = match CommandFailed::find(error) {
+            Some(failed) => (Some(failed.status.clone()), failed.stderr.clone()),
+            None => (None, vec![error.root_cause().to_string()]),
+        };
+        Self {
+            file: file.to_owned(),
+            line,
+            command: secrets.mask_str(command.lines().next().unwrap_or_default()),
+            status,
+            stderr: stderr.iter().map(|x| secrets.mask_str(x)).collect(),
+        }
+    }
+}
+
+/// Table of `failures`, with the stderr tail of each under its row.
+pub fn table(failures: &[Failure]) -> String {
+    let rows: Vec<[String; 4]> = failures
+        .iter()
+        .map(|x| {
+            [
+                x.file.clone(),
+                x.line.map_or_else(|| "-".to_owned(), |x| x.to_string()),
+                x.status.clone().unwrap_or_else(|| "-".to_owned()),
+                x.command.clone(),
+            ]
+        })
+        .collect();
+    let header = ["FILE", "LINE", "STATUS", "COMMAND"].map(str::to_owned);
+    let mut widths = header.clone().map(|x| x.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let write_row = |table: &mut String, row: &[String; 4]| {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        let _ = writeln!(table, "{}", cells.join("  ").trim_end());
+    };
+    let mut table = String::new();
+    write_row(&mut table, &header);
+    for (row, failure) in rows.iter().zip(failures) {
+        write_row(&mut table, row);
+        for line in &failure.stderr {
+            let _ = writeln!(table, "    {line}");
+        }
+    }
+    table
+}
+
+/// Writes `outcomes` in `format`.
+pub fn write(
+    format: ReportFormat,
+    outcomes: &[Outcome],
+    out: &mut impl io::Write,
+) -> io::Result<()> {
+    match format {
+        ReportFormat::Json => {
+            let actions: Vec<_> = outcomes
+                .iter()
+                .map(|x| {
+                    json!({
+                        "file": x.file,
+                        "line": x.line,
+                        "command": x.command,
+                        "duration_ms": x.duration.as_millis() as u64,
+                        "status": x.status,
+                        "output_bytes": x.output_bytes,
+                        "changed": x.changed,
+                        "error": x.error,
+                    })
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut *out, &actions)?;
+            writeln!(out)
+        }
+        ReportFormat::Junit => write_junit(outcomes, out),
+        ReportFormat::Tap => write_tap(outcomes, out),
+    }
+}
+
+/// A test suite per file, with a test case per action.
+fn write_junit(outcomes: &[Outcome], out: &mut impl io::Write) -> io::Result<()> {
+    let failures = |x: &[Outcome]| x.iter().filter(|x| x.error.is_some()).count();
+    let time = |x: &[Outcome]| x.iter().map(|x| x.duration).sum::<Duration>().as_secs_f64();
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuites name="mdsh" tests="{}" failures="{}" time="{:.3}">"#,
+        outcomes.len(),
+        failures(outcomes),
+        time(outcomes)
+    )?;
+    for suite in outcomes.chunk_by(|a, b| a.file == b.file) {
+        let file = xml_escape(&suite[0].file);
+        writeln!(
+            out,
+            r#"  <testsuite name="{file}" tests="{}" failures="{}" time="{:.3}">"#,
+            suite.len(),
+            failures(suite),
+            time(suite)
+        )?;
+        for x in suite {
+            writeln!(
+                out,
+                r#"    <testcase classname="{file}" name="{}" time="{:.3}">"#,
+                xml_escape(&format!("{}:{}: {}", x.file, x.line, x.command)),
+                x.duration.as_secs_f64()
+            )?;
+            writeln!(out, "      <properties>")?;
+            let status = x.status.map(|x| ("status", x.to_string()));
+            for (name, value) in status.into_iter().chain([
+                ("output_bytes", x.output_bytes.to_string()),
+                ("changed", x.changed.to_string()),
+            ]) {
+                writeln!(out, r#"        <property name="{name}" value="{value}"/>"#)?;
+            }
+            writeln!(out, "      </properties>")?;
+            if let Some(error) = &x.error {
+                let message = xml_escape(error.lines().next().unwrap_or_default());
+                writeln!(
+                    out,
+                    r#"      <failure message="{message}">{}</failure>"#,
+                    xml_escape(error)
+                )?;
+            }
+            writeln!(out, "    </testcase>")?;
+        }
+        writeln!(out, "  </testsuite>")?;
+    }
+    writeln!(out, "</testsuites>")
+}
+
+/// A test point per action, with the details in a YAML block.
+fn write_tap(outcomes: &[Outcome], out: &mut impl io::Write) -> io::Result<()> {
+    writeln!(out, "TAP version 13")?;
+    writeln!(out, "1..{}", outcomes.len())?;
+    for (i, x) in outcomes.iter().enumerate() {
+        let ok = if x.error.is_some() { "not ok" } else { "ok" };
+        let description = format!("{}:{}: {}", x.file, x.line, x.command);
+        // `#` would start a directive
+        writeln!(out, "{ok} {} - {}", i + 1, description.replace('#', "\\#"))?;
+        writeln!(out, "  ---")?;
+        writeln!(out, "  duration_ms: {}", x.duration.as_millis())?;
+        if let Some(status) = x.status {
+            writeln!(out, "  status: {status}")?;
+        }
+        writeln!(out, "  output_bytes: {}", x.output_bytes)?;
+        writeln!(out, "  changed: {}", x.changed)?;
+        if let Some(error) = &x.error {
+            // a JSON string is a YAML string as well
+            writeln!(out, "  message: {}", json!(error))?;
+        }
+        writeln!(out, "  ...")?;
+    }
+    Ok(())
+}
+
+/// `text` for XML attributes and content, without the control
+/// characters that XML doesn't allow, like those of colors.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let failed = anyhow::Error::new(CommandFailed {
+            status: "exit status: 1".to_owned(),
+            code: Some(1),
+            stderr: vec!["oops".to_owned()],
+        });
+        let error = anyhow::anyhow!("failed to open file");
+        let secrets = Secrets::default();
+        assert_eq!(
+            table(&[
+                Failure::new("a.md", Some(7), "echo oops >&2; false", &failed, &secrets),
+                Failure::new("b.md", None, "", &error, &secrets),
+            ]),
+            "FILE  LINE  STATUS          COMMAND\n\
+             a.md  7     exit status: 1  echo oops >&2; false\n    oops\n\
+             b.md  -     -\n    failed to open file\n"
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let outcomes = [
+            (1, "echo same", Some(0), None),
+            (
+                9,
+                "exit 3",
+                Some(3),
+                Some("command failed with exit status: 3"),
+            ),
+            (11, "A=1", None, None),
+        ]
+        .map(|(line, command, status, error)| Outcome {
+            file: "<stdin>".to_owned(),
+            line,
+            command: command.to_owned(),
+            duration: Duration::from_millis(12),
+            status,
+            output_bytes: 4,
+            changed: error.is_some(),
+            error: error.map(str::to_owned),
+        });
+        let mut tap = Vec::new();
+        write(ReportFormat::Tap, &outcomes[1..], &mut tap).unwrap();
+        assert_eq!(
+            String::from_utf8(tap).unwrap(),
+            "TAP version 13\n1..2\nnot ok 1 - <stdin>:9: exit 3\n  ---\n  duration_ms: 12\n  \
+             status: 3\n  output_bytes: 4\n  changed: true\n  \
+             message: \"command failed with exit status: 3\"\n  ...\n\
+             ok 2 - <stdin>:11: A=1\n  ---\n  duration_ms: 12\n  output_bytes: 4\n  \
+             changed: false\n  ...\n"
+        );
+        let mut json = Vec::new();
+        write(ReportFormat::Json, &outcomes[..1], &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[0]["file"], "<stdin>");
+        assert_eq!(json[0]["duration_ms"], 12);
+        assert_eq!(json[0]["error"], serde_json::Value::Null);
+        let mut junit = Vec::new();
+        write(ReportFormat::Junit, &outcomes, &mut junit).unwrap();
+        let junit = String::from_utf8(junit).unwrap();
+        assert!(junit.contains(r#"<testsuite name="&lt;stdin&gt;" tests="3" failures="1""#));
+    }
+}
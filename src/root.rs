@@ -0,0 +1,49 @@
+//! Project root that `<` includes and work dirs have to stay in, so that
+//! a document can't read `/etc/passwd` or run commands somewhere else.
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Where the project root is.
+#[derive(Debug, Clone, Default)]
+pub enum Root {
+    /// The closest directory with a `.git`, from the document up.
+    #[default]
+    Detect,
+    Dir(PathBuf),
+    /// No confinement.
+    Anywhere,
+}
+
+impl Root {
+    /// Canonical root for the document in `dir`, `None` for anywhere.
+    pub fn resolve(&self, dir: &Path) -> Option<PathBuf> {
+        match self {
+            Self::Detect => Some(detect(dir)),
+            Self::Dir(root) => Some(root.canonicalize().unwrap_or_else(|_| root.clone())),
+            Self::Anywhere => None,
+        }
+    }
+}
+
+/// The closest directory with a `.git` from `start` up, or `start`.
+pub fn detect(start: &Path) -> PathBuf {
+    let start = start.canonicalize().unwrap_or_else(|_| start.to_owned());
+    start
+        .ancestors()
+        .find(|x| x.join(".git").exists())
+        .unwrap_or(&start)
+        .to_owned()
+}
+
+/// Fails unless `path`, with symlinks resolved, is in `root`.
+pub fn confine(root: &Path, what: &str, path: &Path) -> Result<()> {
+    let resolved = path
+        .canonicalize()
+        .with_context(|| format!("failed to find {what} {path:?}"))?;
+    anyhow::ensure!(
+        resolved.starts_with(root),
+        "{what} {path:?} is outside of the project root {root:?}, see --allow-outside-root"
+    );
+    Ok(())
+}
@@ -0,0 +1,316 @@
+//! Runs commands in Linux namespaces: without network, as `nobody`, with a
+//! read-only file system except for a private `/tmp` and allowed paths.
+//! There is no PID namespace, and nothing hides the files that the user
+//! can read, like those in `$HOME`.
+//!
+//! Everything is prepared before spawning, so that the forked child only
+//! makes system calls, which is all that is safe in a threaded process.
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{Context, Result};
+
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    /// Canonical paths that commands may write to.
+    writable: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    pub fn new(writable: &[PathBuf]) -> Result<Self> {
+        let writable = writable
+            .iter()
+            .map(|x| {
+                x.canonicalize()
+                    .with_context(|| format!("sandbox path {x:?}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { writable })
+    }
+
+    /// Makes `cmd` enter the sandbox before it starts, in `workdir`.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, cmd: &mut process::Command, workdir: &Path) -> Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        let setup = linux::Setup::new(&self.writable, workdir).context("preparing the sandbox")?;
+        // SAFETY: `Setup::enter` only makes system calls, see above
+        unsafe { cmd.pre_exec(move || setup.enter()) };
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _cmd: &mut process::Command, _workdir: &Path) -> Result<()> {
+        anyhow::bail!("the sandbox needs Linux namespaces")
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        ffi::{CStr, CString, OsStr},
+        io::{Error, Result},
+        os::unix::ffi::OsStrExt as _,
+        path::{Path, PathBuf},
+    };
+
+    use libc::c_ulong;
+
+    /// Where the paths to bind are kept open while `/tmp` gets replaced,
+    /// as they may be in there.
+    const FD_BASE: i32 = 900;
+
+    /// File systems that have nothing to write to, or can't be remounted.
+    const PSEUDO_FS: &[&str] = &[
+        "autofs",
+        "binfmt_misc",
+        "bpf",
+        "cgroup",
+        "cgroup2",
+        "configfs",
+        "debugfs",
+        "devpts",
+        "fusectl",
+        "hugetlbfs",
+        "mqueue",
+        "nsfs",
+        "proc",
+        "pstore",
+        "securityfs",
+        "sysfs",
+        "tracefs",
+    ];
+
+    pub struct Setup {
+        uid_map: CString,
+        gid_map: CString,
+        /// Mounts to make read-only, with the flags they have to keep.
+        mounts: Vec<(CString, c_ulong)>,
+        /// Paths to bind back in after `/tmp`, with their flags, whether
+        /// they're writable, and the directories to create for them.
+        binds: Vec<Bind>,
+        workdir: CString,
+    }
+
+    struct Bind {
+        path: CString,
+        fd_path: CString,
+        flags: c_ulong,
+        writable: bool,
+        is_dir: bool,
+        /// The path needs to be created in the new `/tmp`, after these.
+        parents: Option<Vec<CString>>,
+    }
+
+    impl Setup {
+        pub fn new(writable: &[PathBuf], workdir: &Path) -> anyhow::Result<Self> {
+            // SAFETY: these can't fail
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            let workdir = workdir.canonicalize()?;
+
+            let mut mounts = Vec::new();
+            for line in std::fs::read_to_string("/proc/self/mountinfo")?.lines() {
+                let (fields, fs) = line.split_once(" - ").unwrap_or((line, ""));
+                let (Some(point), Some(fs_type)) = (fields.split(' ').nth(4), fs.split(' ').next())
+                else {
+                    continue;
+                };
+                let point = unescape(point);
+                if PSEUDO_FS.contains(&fs_type) || mounts.iter().any(|(x, _)| *x == point) {
+                    continue;
+                }
+                // hidden or out of reach, and so for the commands too
+                let Ok(flags) = flags(Path::new(OsStr::from_bytes(point.as_bytes()))) else {
+                    continue;
+                };
+                mounts.push((point, flags));
+            }
+
+            let binds = std::iter::once((&workdir, false))
+                .chain(writable.iter().map(|x| (x, true)))
+                .enumerate()
+                .map(|(i, (path, writable))| {
+                    let parents = path.starts_with("/tmp").then(|| {
+                        let mut parents = path
+                            .ancestors()
+                            .skip(1)
+                            .take_while(|x| *x != Path::new("/tmp"))
+                            .map(cstring)
+                            .collect::<Vec<_>>();
+                        parents.reverse();
+                        parents
+                    });
+                    Ok(Bind {
+                        path: cstring(path),
+                        fd_path: CString::new(format!("/proc/self/fd/{}", FD_BASE + i as i32))?,
+                        flags: flags(path)?,
+                        writable,
+                        is_dir: path.is_dir(),
+                        parents,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            Ok(Self {
+                uid_map: CString::new(format!("65534 {uid} 1"))?,
+                gid_map: CString::new(format!("65534 {gid} 1"))?,
+                mounts,
+                binds,
+                workdir: cstring(&workdir),
+            })
+        }
+
+        /// Runs in the forked child, right before `exec`.
+        pub fn enter(&self) -> Result<()> {
+            // SAFETY: system calls on pointers that outlive them
+            unsafe {
+                check(libc::unshare(
+                    libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET,
+                ))?;
+                write(c"/proc/self/setgroups", c"deny")?;
+                write(c"/proc/self/uid_map", &self.uid_map)?;
+                write(c"/proc/self/gid_map", &self.gid_map)?;
+                mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+
+                for (i, bind) in self.binds.iter().enumerate() {
+                    let fd = check(libc::open(
+                        bind.path.as_ptr(),
+                        libc::O_PATH | libc::O_CLOEXEC,
+                    ))?;
+                    check(libc::dup3(fd, FD_BASE + i as i32, libc::O_CLOEXEC))?;
+                    check(libc::close(fd))?;
+                }
+
+                for (point, flags) in &self.mounts {
+                    let flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags;
+                    mount(None, point, None, flags, None)?;
+                }
+                mount(
+                    Some(c"tmpfs"),
+                    c"/tmp",
+                    Some(c"tmpfs"),
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                    Some(c"mode=1777"),
+                )?;
+
+                for bind in &self.binds {
+                    if let Some(parents) = &bind.parents {
+                        for parent in parents {
+                            mkdir(parent)?;
+                        }
+                        if bind.is_dir {
+                            mkdir(&bind.path)?;
+                        } else {
+                            let flags = libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC;
+                            check(libc::close(check(libc::open(
+                                bind.path.as_ptr(),
+                                flags,
+                                0o644,
+                            ))?))?;
+                        }
+                    }
+                    mount(
+                        Some(&bind.fd_path),
+                        &bind.path,
+                        None,
+                        libc::MS_BIND | libc::MS_REC,
+                        None,
+                    )?;
+                    let ro = if bind.writable { 0 } else { libc::MS_RDONLY };
+                    let flags = libc::MS_REMOUNT | libc::MS_BIND | ro | bind.flags;
+                    mount(None, &bind.path, None, flags, None)?;
+                }
+
+                // the old working directory is on the mount before the binds
+                check(libc::chdir(self.workdir.as_ptr()))?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Mount flags that a remount has to keep, as it isn't allowed to
+    /// drop them in a user namespace.
+    fn flags(path: &Path) -> anyhow::Result<c_ulong> {
+        let path = cstring(path);
+        // SAFETY: statvfs only writes to the struct
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        check(unsafe { libc::statvfs(path.as_ptr(), &mut stat) })?;
+        Ok([
+            (libc::ST_NOSUID, libc::MS_NOSUID),
+            (libc::ST_NODEV, libc::MS_NODEV),
+            (libc::ST_NOEXEC, libc::MS_NOEXEC),
+            (libc::ST_NOATIME, libc::MS_NOATIME),
+            (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+            (libc::ST_RELATIME, libc::MS_RELATIME),
+        ]
+        .into_iter()
+        .filter(|(st, _)| stat.f_flag & st != 0)
+        .fold(0, |flags, (_, ms)| flags | ms))
+    }
+
+    /// Mount points in mountinfo have spaces and such escaped as `\040`.
+    fn unescape(s: &str) -> CString {
+        let mut out = Vec::with_capacity(s.len());
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'\\' {
+                let octal: String = bytes.by_ref().take(3).map(char::from).collect();
+                out.push(u8::from_str_radix(&octal, 8).unwrap_or(b'?'));
+            } else {
+                out.push(b);
+            }
+        }
+        CString::new(out).unwrap_or_default()
+    }
+
+    fn cstring(path: &Path) -> CString {
+        CString::new(path.as_os_str().as_bytes()).unwrap_or_default()
+    }
+
+    fn check(ret: i32) -> Result<i32> {
+        if ret < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    unsafe fn write(path: &CStr, content: &CStr) -> Result<()> {
+        let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+        let len = content.to_bytes().len();
+        let written = libc::write(fd, content.as_ptr().cast(), len);
+        libc::close(fd);
+        if written != len as isize {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    unsafe fn mkdir(path: &CStr) -> Result<()> {
+        if libc::mkdir(path.as_ptr(), 0o755) < 0 {
+            let e = Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EEXIST) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn mount(
+        source: Option<&CStr>,
+        target: &CStr,
+        fs_type: Option<&CStr>,
+        flags: c_ulong,
+        data: Option<&CStr>,
+    ) -> Result<()> {
+        let ptr = |x: Option<&CStr>| x.map_or(std::ptr::null(), CStr::as_ptr);
+        check(libc::mount(
+            ptr(source),
+            target.as_ptr(),
+            ptr(fs_type),
+            flags,
+            ptr(data).cast(),
+        ))
+        .map(|_| ())
+    }
+}
@@ -0,0 +1,277 @@
+//! Long-lived shell that runs all of a document's commands, so that
+//! `cd`, functions and variables carry over from one block to the next.
+use std::{
+    collections::BTreeMap as Map,
+    ffi::OsStr,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{self, ChildStderr, ChildStdin, ChildStdout, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    executor::{CommandFailed, Environment, Shell, StderrTail, Watchdog},
+    sandbox::Sandbox,
+};
+
+/// Reads every block up to the line with its end, and runs it in a subshell
+/// with the prologue, which goes on with the next blocks if it succeeds.
+/// A failed block therefore neither ends the session nor leaves anything
+/// behind in it, and neither does one that runs `exit`. Only the end of the
+/// input exits with [`END`], which ends every subshell up to the session.
+const DRIVER: &str = r#"__mdsh_options=$(set +o)
+__mdsh_done() {
+    printf '%s\n' "$__mdsh_end" >&2
+    printf '%s %d\n' "$__mdsh_end" "$1"
+}
+__mdsh_serve() {
+    while IFS= read -r __mdsh_end; do
+        __mdsh_script=
+        while IFS= read -r __mdsh_line && [ "$__mdsh_line" != "$__mdsh_end" ]; do
+            __mdsh_script="$__mdsh_script$__mdsh_line
+"
+        done
+        (
+            eval "$__mdsh_script"
+            __mdsh_status=$?
+            [ "$__mdsh_status" = 0 ] || exit "$__mdsh_status"
+            __mdsh_done 0
+            eval "$__mdsh_options"
+            __mdsh_serve
+        )
+        __mdsh_status=$?
+        [ "$__mdsh_status" = 255 ] && exit 255
+        __mdsh_done "$__mdsh_status"
+    done
+    exit 255
+}
+__mdsh_serve
+"#;
+
+/// The status that the [`DRIVER`] reserves for the end of the session.
+const END: i32 = 255;
+
+#[derive(Debug)]
+pub struct Session {
+    child: process::Child,
+    /// `None` once the session is over.
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    /// The last lines of stderr of every command.
+    stderr: mpsc::Receiver<Vec<String>>,
+    shell: Shell,
+    /// Unique per session, so that output can't fake the end of a command.
+    token: String,
+    count: usize,
+}
+
+impl Session {
+    pub fn spawn(
+        shell: &Shell,
+        workdir: &OsStr,
+        env: Option<&Environment>,
+        variables: &Map<String, String>,
+        sandbox: Option<&Sandbox>,
+    ) -> Result<Self> {
+        let mut cmd = shell.command()?;
+        if let Some(sandbox) = sandbox {
+            sandbox.apply(&mut cmd, Path::new(workdir))?;
+        }
+        // so that a timeout can kill the session and everything it runs
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        if let Some(env) = env {
+            env.apply(&mut cmd);
+        }
+        let mut child = cmd
+            .envs(variables)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .current_dir(workdir)
+            .spawn()
+            .with_context(|| format!("failed to run {:?}", cmd.get_program()))?;
+        let stdin = child
+            .stdin
+            .take()
+            .context("shell didn't provide stdin pipe")?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("shell didn't provide stdout pipe")?;
+        let stderr = child
+            .stderr
+            .take()
+            .context("shell didn't provide stderr pipe")?;
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let token = format!("__mdsh_{}_{nanos:x}", child.id());
+        let mut session = Self {
+            stderr: tail(stderr, token.clone()),
+            token,
+            child,
+            stdin: Some(stdin),
+            stdout: BufReader::new(stdout),
+            shell: shell.clone(),
+            count: 0,
+        };
+        session.send(DRIVER)?;
+        Ok(session)
+    }
+
+    /// Runs `code` with `data` on its stdin, returns what it printed.
+    /// `variables` are exported first, as `!` may have changed them.
+    pub fn run(
+        &mut self,
+        code: &str,
+        data: Option<&str>,
+        variables: &Map<String, String>,
+        timeout: Option<(Duration, String)>,
+    ) -> Result<Vec<u8>> {
+        self.count += 1;
+        let end = format!("{}_{}", self.token, self.count);
+
+        let mut script = String::with_capacity(code.len() + 256);
+        for (k, v) in variables {
+            script += &format!("export {k}={}\n", quote(v));
+        }
+        // a group keeps the state, but doesn't read the rest of the session
+        // from the shell's stdin
+        script += &format!("{{\n{}\n}}", code.trim_end_matches('\n'));
+        match data {
+            Some(data) => {
+                script += &format!(" <<'{end}.'\n{data}");
+                if !data.is_empty() && !data.ends_with('\n') {
+                    script.push('\n');
+                }
+                script += &format!("{end}.\n");
+            }
+            None => script += " </dev/null\n",
+        }
+        let script = format!("{end}\n{}{end}\n", self.shell.script(&script));
+
+        let mut watchdog =
+            timeout.map(|(timeout, message)| Watchdog::new(self.child.id(), timeout, message));
+        self.send(&script)?;
+
+        let mut output = Vec::with_capacity(8192);
+        let status = loop {
+            let start = output.len();
+            let n = self
+                .stdout
+                .read_until(b'\n', &mut output)
+                .context("reading from the session")?;
+            if n == 0 {
+                if let Some(message) = watchdog.take().and_then(Watchdog::cancel) {
+                    anyhow::bail!(message);
+                }
+                let status = self.child.wait()?;
+                if status.code() == Some(END) {
+                    anyhow::bail!("a command exited with status {END}, which ends the session");
+                }
+                anyhow::bail!("the session shell terminated with {status}");
+            }
+            let line = &output[start..];
+            if let Some(at) = find(line, end.as_bytes()) {
+                let status = String::from_utf8_lossy(&line[at + end.len()..]);
+                let status = status.trim().parse::<i32>().unwrap_or(-1);
+                output.truncate(start + at);
+                break status;
+            }
+        };
+        if let Some(message) = watchdog.and_then(Watchdog::cancel) {
+            anyhow::bail!(message);
+        }
+        // the end goes to stderr before stdout, so it's there already
+        let stderr = self.stderr.recv().unwrap_or_default();
+        if status != 0 {
+            return Err(CommandFailed {
+                status: format!("exit status: {status}"),
+                code: Some(status),
+                stderr,
+            }
+            .into());
+        }
+        Ok(output)
+    }
+
+    fn send(&mut self, script: &str) -> Result<()> {
+        let stdin = self.stdin.as_mut().context("the session is over")?;
+        stdin
+            .write_all(script.as_bytes())
+            .and_then(|_| stdin.flush())
+            .context("writing to the session")
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // the shell exits at the end of its input
+        self.stdin = None;
+        let _ = self.child.wait();
+    }
+}
+
+/// Passes the stderr of the session on to mdsh's as it comes, and sends
+/// the last lines of every command once the line with its end comes.
+fn tail(stderr: ChildStderr, token: String) -> mpsc::Receiver<Vec<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stderr = BufReader::new(stderr);
+        let mut line = Vec::new();
+        let mut tail = Vec::new();
+        loop {
+            line.clear();
+            let Ok(1..) = stderr.read_until(b'\n', &mut line) else {
+                break;
+            };
+            let end = find(&line, token.as_bytes());
+            let line = &line[..end.unwrap_or(line.len())];
+            let _ = std::io::stderr().write_all(line);
+            tail.extend_from_slice(line);
+            if end.is_some() {
+                if sender.send(StderrTail::lines(&tail)).is_err() {
+                    break;
+                }
+                tail.clear();
+            } else if tail.len() > 16384 {
+                tail.drain(..tail.len() - 8192);
+            }
+        }
+    });
+    receiver
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit() {
+        let variables = Map::new();
+        let mut session =
+            Session::spawn(&Shell::default(), OsStr::new("."), None, &variables, None).unwrap();
+        let mut run = |code| session.run(code, None, &variables, None);
+
+        assert_eq!(run("x=1; echo $x").unwrap(), b"1\n");
+        // `exit` ends the block, and takes what it did with it
+        assert_eq!(run("x=2; echo $x; exit 0").unwrap(), b"2\n");
+        let error = run("x=3; echo $x; exit 3").unwrap_err();
+        assert_eq!(error.downcast_ref::<CommandFailed>().unwrap().code, Some(3));
+        assert_eq!(run("echo $x").unwrap(), b"1\n");
+    }
+}
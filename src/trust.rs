@@ -0,0 +1,238 @@
+//! Which documents may run their commands, like direnv's `allow`: a
+//! freshly cloned document only runs commands that were allowed before,
+//! with the shell, prologue and interpreters that they were allowed with.
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    cache::KeyBuilder,
+    cli::FileArg,
+    executor::{Action, InType, OutType, Runner, Settings},
+    MdPiece, Processor,
+};
+
+/// A `$` or `!` action of a document.
+#[derive(Debug)]
+pub struct Command {
+    pub line: usize,
+    /// Its data line, or the first line of its script.
+    pub summary: String,
+    /// What runs it, unless it's a `!` action without a command.
+    pub runner: Option<String>,
+    /// Whether the shell runs it.
+    pub shell: bool,
+    hash: String,
+}
+
+/// The `$` and `!` actions of a document, found without running anything.
+/// `!` actions count too, as variables like `BASH_ENV` change what the
+/// commands after them run.
+pub fn commands(document: &Path, content: &str, settings: &Settings) -> Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    Collector {
+        input: content,
+        settings,
+        commands: &mut commands,
+    }
+    .process(content, &FileArg::File(document.to_owned()))?;
+    Ok(commands)
+}
+
+struct Collector<'a> {
+    input: &'a str,
+    settings: &'a Settings,
+    commands: &'a mut Vec<Command>,
+}
+
+impl<'a> Processor<'a> for Collector<'a> {
+    fn start(&mut self, input: &'a str, _input_pipe: &FileArg) {
+        self.input = input;
+    }
+
+    fn process_piece(&mut self, piece: MdPiece<'a>) -> Result<()> {
+        match piece {
+            MdPiece::Action((_, action)) => {
+                if let (InType::Execute, _) | (_, OutType::Environment) =
+                    (&action.command.in_type, &action.command.out_type)
+                {
+                    self.commands.push(command(
+                        crate::position(self.input, action.span.start).0,
+                        &action,
+                        self.settings,
+                    ));
+                }
+            }
+            MdPiece::Container(container) => {
+                for piece in container.pieces {
+                    self.process_piece(piece)?;
+                }
+            }
+            MdPiece::FencedBlock(_) | MdPiece::Raw(_) => (),
+        }
+        Ok(())
+    }
+}
+
+fn command(line: usize, action: &Action, settings: &Settings) -> Command {
+    let mut hash = KeyBuilder::default();
+    let kind = match (&action.command.in_type, &action.command.out_type) {
+        (InType::Execute, OutType::Environment) => "! $",
+        (InType::Execute, _) => "$",
+        (InType::Read, _) => "! <",
+        (InType::RawData, _) => "!",
+    };
+    hash.add("kind", kind);
+    if let InType::Execute = action.command.in_type {
+        // what the command runs with matters as much as the command
+        hash.add("shell", &settings.shell.command)
+            .add("prologue", &settings.shell.prologue);
+        if let Some(lang) = &action.lang {
+            let interpreter = settings.interpreters.0.get(lang.as_ref());
+            hash.add("interpreter", interpreter.map_or("", String::as_str));
+        }
+    }
+    if let Some(lang) = &action.lang {
+        hash.add("lang", lang.as_bytes());
+    }
+    if let Some(data_line) = &action.data_line {
+        hash.add("command", data_line.as_bytes());
+    }
+    if let Some(data) = &action.data {
+        hash.add("data", data.as_bytes());
+    }
+    let script = action.data.as_deref().unwrap_or_default();
+    let summary = match (&action.data_line, script.lines().next()) {
+        (Some(data_line), _) => data_line.to_string(),
+        (None, Some(first)) if script.lines().nth(1).is_some() => format!("{first} ..."),
+        (None, first) => first.unwrap_or_default().to_owned(),
+    };
+    let summary = match kind {
+        "$" => summary,
+        kind => format!("{kind} {summary}"),
+    };
+    let runner = settings.runner(action);
+    Command {
+        line,
+        summary,
+        shell: matches!(runner, Some(Runner::Shell { .. })),
+        runner: runner.map(|x| x.to_string()),
+        hash: hash.finish().as_str().to_owned(),
+    }
+}
+
+/// Directory with a file per allowed document, named by the hash of its
+/// path, with the hashes of the commands that it may run.
+#[derive(Debug, Clone)]
+pub struct TrustStore {
+    dir: PathBuf,
+}
+
+impl TrustStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// `$XDG_DATA_HOME/mdsh/trust`, or `~/.local/share/mdsh/trust`.
+    pub fn default_dir() -> Result<PathBuf> {
+        let data = std::env::var_os("XDG_DATA_HOME")
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|x| Path::new(&x).join(".local").join("share"))
+            })
+            .context("neither XDG_DATA_HOME nor HOME is set, use --trust-dir")?;
+        Ok(data.join("mdsh").join("trust"))
+    }
+
+    /// Lets `document` run `commands` from now on, and nothing else.
+    pub fn allow(&self, document: &Path, commands: &[Command]) -> Result<()> {
+        let allowed = commands
+            .iter()
+            .map(|x| format!("{}\n", x.hash))
+            .collect::<BTreeSet<_>>();
+        let path = self.path(document)?;
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&path, allowed.into_iter().collect::<String>()))
+            .with_context(|| format!("writing trust store in {:?}", self.dir))
+    }
+
+    /// Commands of `document` that weren't allowed.
+    pub fn untrusted<'c>(
+        &self,
+        document: &Path,
+        commands: &'c [Command],
+    ) -> Result<Vec<&'c Command>> {
+        let allowed = match fs::read_to_string(self.path(document)?) {
+            Ok(allowed) => allowed,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => Err(e).with_context(|| format!("reading trust store in {:?}", self.dir))?,
+        };
+        let allowed = allowed.lines().collect::<BTreeSet<_>>();
+        Ok(commands
+            .iter()
+            .filter(|x| !allowed.contains(x.hash.as_str()))
+            .collect())
+    }
+
+    fn path(&self, document: &Path) -> Result<PathBuf> {
+        let document = document
+            .canonicalize()
+            .with_context(|| format!("failed to find {document:?}"))?;
+        let mut key = KeyBuilder::default();
+        key.add("document", document.as_os_str().as_encoded_bytes());
+        Ok(self.dir.join(key.finish().as_str()))
+    }
+}
+
+/// Fails with the commands of `document` that weren't allowed to run with
+/// `settings`, if any.
+pub fn check(
+    store: &TrustStore,
+    document: &Path,
+    content: &str,
+    settings: &Settings,
+) -> Result<()> {
+    let commands = commands(document, content, settings)?;
+    let untrusted = store.untrusted(document, &commands)?;
+    if untrusted.is_empty() {
+        return Ok(());
+    }
+    let name = document.display();
+    let mut message = format!("{name} has commands that weren't allowed to run:\n");
+    for command in &untrusted {
+        message += &format!("  {name}:{}: {}", command.line, command.summary);
+        match &command.runner {
+            Some(runner) if !command.shell => message += &format!(", with {runner}\n"),
+            _ => message.push('\n'),
+        }
+    }
+    if let Some(shell) = untrusted.iter().find(|x| x.shell) {
+        let shell = shell.runner.as_deref().unwrap_or_default();
+        message += &format!("Shell commands run with {shell}");
+        match &settings.config {
+            Some(config) => message += &format!(", as {} says.\n", config.display()),
+            None => message += ".\n",
+        }
+    } else if let Some(config) = &settings.config {
+        message += &format!("{} sets what they run with.\n", config.display());
+    }
+    message += &format!(
+        "Review them, for example with `mdsh --plan -i {name}`, then run `mdsh allow {name}`."
+    );
+    anyhow::bail!(message)
+}
+
+/// Allows the current commands of `document` to run with `settings`.
+pub fn allow(store: &TrustStore, document: &Path, settings: &Settings) -> Result<usize> {
+    let content = fs::read_to_string(document)
+        .with_context(|| format!("failed to read file {document:?}"))?;
+    let commands = commands(document, &content, settings)?;
+    store.allow(document, &commands)?;
+    Ok(commands.len())
+}